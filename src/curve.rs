@@ -0,0 +1,176 @@
+//! Parameters that change over the course of a run.
+use crate::{dla::Square, Result};
+use serde_derive::Deserialize;
+
+/// A parameter value that can change while the aggregate grows.
+///
+/// In a config file this is either a single number, a pair of numbers
+/// that is interpolated linearly from the first to the last particle
+/// or a table of keyframes:
+///
+/// ```toml
+/// stickiness = { by = "radius", interpolation = "smoothstep", keys = [[0, 1.0], [50, 0.2]] }
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Curve {
+    Constant(f32),
+    Range([f32; 2]),
+    Keyframes(Keyframes),
+}
+
+/// Keyframes of a [`Curve`].
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Keyframes {
+    /// What the keys are positioned by: `"particles"`, the fraction
+    /// of particles placed (from 0 to 1), or `"radius"`, the distance
+    /// of the furthest particle from the origin.
+    pub by: Option<String>,
+    /// `"linear"`, `"smoothstep"` or `"bezier"` (smooth Bézier
+    /// segments through all keys).
+    pub interpolation: Option<String>,
+    /// Pairs of position & value, sorted by position. Before the
+    /// first and after the last key the curve stays constant.
+    pub keys: Vec<[f32; 2]>,
+}
+
+impl From<f32> for Curve {
+    fn from(value: f32) -> Self {
+        Curve::Constant(value)
+    }
+}
+
+impl Curve {
+    /// Fails if the curve can not be evaluated; `name` is the config
+    /// key it came from.
+    pub fn check(&self, name: &str) -> Result<()> {
+        match self {
+            Curve::Keyframes(keyframes) if keyframes.keys.is_empty() => {
+                Err(format!("The keyframes of '{}' need at least one key.", name).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the value after `fraction` of the particles were
+    /// placed and with the furthest particle `radius` away from the
+    /// origin.
+    pub fn evaluate(&self, fraction: f32, radius: f32) -> f32 {
+        match self {
+            Curve::Constant(value) => *value,
+            Curve::Range([first, last]) => first * (1.0 - fraction) + last * fraction,
+            Curve::Keyframes(keyframes) => {
+                keyframes.evaluate(match keyframes.by.as_deref().unwrap_or("particles") {
+                    "radius" => radius,
+                    _ => fraction,
+                })
+            }
+        }
+    }
+}
+
+impl Keyframes {
+    fn evaluate(&self, x: f32) -> f32 {
+        let keys = &self.keys;
+        match keys.len() {
+            0 => panic!("A keyframe curve needs at least one key."),
+            1 => return keys[0][1],
+            _ => (),
+        }
+        if x <= keys[0][0] {
+            return keys[0][1];
+        }
+        if keys[keys.len() - 1][0] <= x {
+            return keys[keys.len() - 1][1];
+        }
+
+        // Index of the key starting the segment containing x.
+        let i = keys.windows(2).position(|pair| x < pair[1][0]).unwrap();
+        let [x0, y0] = keys[i];
+        let [x1, y1] = keys[i + 1];
+        let width = x1 - x0;
+        let t = if 0.0 < width { (x - x0) / width } else { 1.0 };
+
+        match self.interpolation.as_deref().unwrap_or("linear") {
+            "smoothstep" => y0 + (y1 - y0) * t.square() * (3.0 - 2.0 * t),
+            "bezier" => {
+                // Handles a third of the segment away, along the
+                // slope through the neighbouring keys (Catmull-Rom).
+                let slope = |i: usize| {
+                    let [xa, ya] = keys[i.saturating_sub(1)];
+                    let [xb, yb] = keys[(i + 1).min(keys.len() - 1)];
+                    if 0.0 < xb - xa {
+                        (yb - ya) / (xb - xa)
+                    } else {
+                        0.0
+                    }
+                };
+                let handle0 = y0 + slope(i) * width / 3.0;
+                let handle1 = y1 - slope(i + 1) * width / 3.0;
+                let s = 1.0 - t;
+                s * s * s * y0
+                    + 3.0 * s * s * t * handle0
+                    + 3.0 * s * t * t * handle1
+                    + t * t * t * y1
+            }
+            _ => y0 + (y1 - y0) * t,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframes(interpolation: &str) -> Keyframes {
+        Keyframes {
+            by: None,
+            interpolation: Some(interpolation.to_string()),
+            keys: vec![[0.2, 1.0], [0.5, 3.0], [0.8, 2.0]],
+        }
+    }
+
+    #[test]
+    fn keyframes_clamp_outside_the_keys() {
+        for interpolation in &["linear", "smoothstep", "bezier"] {
+            let keyframes = keyframes(interpolation);
+            assert_eq!(keyframes.evaluate(0.0), 1.0);
+            assert_eq!(keyframes.evaluate(0.2), 1.0);
+            assert_eq!(keyframes.evaluate(0.8), 2.0);
+            assert_eq!(keyframes.evaluate(1.0), 2.0);
+        }
+    }
+
+    #[test]
+    fn keyframes_pass_through_the_keys() {
+        for interpolation in &["linear", "smoothstep", "bezier"] {
+            let keyframes = keyframes(interpolation);
+            assert!((keyframes.evaluate(0.5) - 3.0).abs() < 1e-6);
+            // Just inside the segments the value is close to the key.
+            assert!((keyframes.evaluate(0.2001) - 1.0).abs() < 1e-2);
+            assert!((keyframes.evaluate(0.7999) - 2.0).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn keyframes_interpolate_between_the_keys() {
+        assert!((keyframes("linear").evaluate(0.35) - 2.0).abs() < 1e-6);
+        assert!((keyframes("smoothstep").evaluate(0.35) - 2.0).abs() < 1e-6);
+        // Handles at 1 + 20/3 * 0.1 and 3 - 5/3 * 0.1.
+        assert!((keyframes("bezier").evaluate(0.35) - 2.1875).abs() < 1e-5);
+    }
+
+    #[test]
+    fn curve_by_radius_uses_the_radius() {
+        let curve = Curve::Keyframes(Keyframes {
+            by: Some("radius".to_string()),
+            interpolation: None,
+            keys: vec![[0.0, 0.0], [10.0, 1.0]],
+        });
+        assert!((curve.evaluate(1.0, 5.0) - 0.5).abs() < 1e-6);
+        assert!(curve.check("test").is_ok());
+        assert!(Curve::Keyframes(Keyframes::default())
+            .check("test")
+            .is_err());
+    }
+}
@@ -1,25 +1,34 @@
 use bytemuck as bm;
 use nalgebra::Vector3;
 use ply_rs::{
+    parser::Parser,
     ply::{
-        Addable, DefaultElement, ElementDef, Encoding, Ply, Property, PropertyDef, PropertyType,
+        Addable, DefaultElement, ElementDef, Encoding, Header, Property, PropertyDef, PropertyType,
         ScalarType,
     },
     writer::Writer,
 };
-use rand::{distributions::Distribution, Rng};
-use rand_distr::UnitSphere;
+use rand::{distributions::Distribution, Rng, RngCore};
+use rand_distr::{LogNormal, Normal, UnitSphere};
 use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256Plus};
 use rstar::{primitives::PointWithData, RStarInsertionStrategy, RTree, RTreeParams};
-use std::{env, fs::File, path::Path};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
 
 pub use crate::*;
 
-type Index = usize;
+pub type Index = usize;
 
 pub type Point3D = Vector3<f32>;
 
-trait Square {
+pub(crate) trait Square {
     fn square(&self) -> Self;
 }
 
@@ -30,14 +39,11 @@ impl Square for f32 {
     }
 }
 
-#[inline]
-fn lerp_points(a: &Point3D, b: &Point3D, d: f32) -> Point3D {
-    a + (b - a).normalize() * d
-}
-
-#[inline]
-fn lerp(a: f32, b: f32, l: f32) -> f32 {
-    a * (1.0 - l) + b * l
+/// Returns a random, uniformly distributed point on the unit
+/// sphere.
+pub fn random_point_on_unit_sphere(rng: &mut dyn RngCore) -> Point3D {
+    let v: [f32; 3] = UnitSphere.sample(rng);
+    Point3D::new(v[0], v[1], v[2])
 }
 
 type IndexValue = PointWithData<Index, [f32; 3]>;
@@ -52,42 +58,910 @@ impl RTreeParams for Params {
 
 type Tree = RTree<IndexValue /* , Params */>;
 
-pub struct Model {
-    config: Config,
+/// Walkers check the time budget every this many steps.
+const STEPS_PER_TIME_CHECK: u64 = 1024;
+/// Columns `dump.csv_columns` can add after `id,parent,x,y,z`.
+const CSV_COLUMNS: [&str; 5] = ["scale", "radius", "cluster", "age", "depth"];
+
+/// Why [`Model::run()`] stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// All particles were placed.
+    Particles,
+    /// The time budget ran out.
+    Time,
+    /// The aggregate grew to the target radius.
+    Radius,
+    /// A particle reached the limit along an axis.
+    Reach,
+    /// Too many walkers in a row were abandoned.
+    Abandoned,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Particles => "all particles placed",
+            StopReason::Time => "time budget used up",
+            StopReason::Radius => "target radius reached",
+            StopReason::Reach => "bounds reached",
+            StopReason::Abandoned => "walkers keep getting abandoned",
+        })
+    }
+}
+
+/// How the walk of a single particle ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Diffusion {
+    /// The particle joined the aggregate or the substrate.
+    Stuck,
+    /// The walker took too many steps.
+    Abandoned,
+    /// The time budget ran out during the walk.
+    OutOfTime,
+}
+
+/// How particle scales vary around the value `particle.scale`
+/// prescribes for the current particle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizeDistribution {
+    /// Every particle gets exactly that scale.
+    Constant,
+    /// Uniform between `1 - deviation` and `1 + deviation` times
+    /// the scale.
+    Uniform(f32),
+    /// Normal with the scale as mean and `deviation` times the scale
+    /// as standard deviation.
+    Normal(f32),
+    /// Log-normal with the scale as median and `deviation` as the
+    /// standard deviation of its logarithm.
+    LogNormal(f32),
+}
+
+impl SizeDistribution {
+    /// Draws a scale from the distribution.
+    pub fn sample(&self, scale: f32, rng: &mut dyn RngCore) -> f32 {
+        let factor = match *self {
+            SizeDistribution::Constant => return scale,
+            SizeDistribution::Uniform(deviation) => rng.gen_range(-deviation..=deviation) + 1.0,
+            SizeDistribution::Normal(deviation) => Normal::new(1.0, deviation)
+                .expect("Size deviation must not be negative.")
+                .sample(rng),
+            SizeDistribution::LogNormal(deviation) => LogNormal::new(0.0, deviation)
+                .expect("Size deviation must not be negative.")
+                .sample(rng),
+        };
+        // The tail of the normal distribution would give zero or
+        // negative scales.
+        scale * factor.max(0.01)
+    }
+}
+
+/// The particles aggregated so far and the parameters steering
+/// their growth.
+///
+/// This is what the [strategies](crate::strategy) get to see.
+pub struct Aggregate {
     particle_spacing: f32,
     attraction_distance: f32,
     repulsion_distance: f32,
     stickiness: f32,
-    bounding_radius: f32,
     stubbornness: u8,
-    join_attempts: Vec<u8>,
+    sized_particles: bool,
+    max_scale: f32,
+    radius: f32,
+    bounding_radius: f32,
+    bounding_box: Option<(Point3D, Point3D)>,
+    periodic_box: Option<Point3D>,
     particles: Vec<(Point3D, f32)>,
+    parents: Vec<Option<Index>>,
+    clusters: Vec<u32>,
+    ages: Vec<u32>,
+    number_of_clusters: u32,
     tree: Tree,
-    rng: Xoshiro256Plus,
 }
 
-impl Model {
-    pub fn new(config: &Config) -> Model {
-        Model {
+impl Aggregate {
+    fn new(config: &Config) -> Aggregate {
+        Aggregate {
             // Parameters from config.
-            config: config.clone(),
-
-            attraction_distance: config.aggregation.attraction_distance.unwrap_or(3.0),
-            repulsion_distance: config.aggregation.repulsion_distance.unwrap_or(1.0),
-            stubbornness: config.aggregation.stubbornness.unwrap_or(0),
-            stickiness: config.aggregation.stickiness.unwrap_or(1.0),
-            // Parameters modified during run().
+            // These can change over the run; see
+            // Model::update_parameters().
+            attraction_distance: 3.0,
+            repulsion_distance: 1.0,
+            stubbornness: 0,
+            stickiness: 1.0,
+            sized_particles: "radii" == config.aggregation.contact.as_deref().unwrap_or("spacing"),
             particle_spacing: 1.0,
             // Output members.
+            max_scale: 0.0,
+            radius: 0.0,
             bounding_radius: 0.0,
-            join_attempts: Vec::new(),
+            bounding_box: None,
+            periodic_box: config
+                .aggregation
+                .periodic_box
+                .map(|size| Point3D::new(size[0], size[1], size[2]))
+                .or_else(|| {
+                    // Deposition wraps around sideways.
+                    config.aggregation.deposition.as_ref().map(|deposition| {
+                        let width = deposition.width.unwrap_or(200.0);
+                        Point3D::new(width, 0.0, width)
+                    })
+                }),
             particles: Vec::new(),
+            parents: Vec::new(),
+            clusters: Vec::new(),
+            ages: Vec::new(),
+            number_of_clusters: 0,
             tree: Tree::new_with_params(),
-            rng: Xoshiro256Plus::seed_from_u64(config.aggregation.random_seed.unwrap_or(42)),
         }
     }
 
-    pub fn run(&mut self) {
+    /// Distance at which a particle gets placed from its parent.
+    #[inline]
+    pub fn particle_spacing(&self) -> f32 {
+        self.particle_spacing
+    }
+
+    /// Distance below which a walker is considered to touch a
+    /// particle.
+    #[inline]
+    pub fn attraction_distance(&self) -> f32 {
+        self.attraction_distance
+    }
+
+    /// Minimum step length of a walker and the distance it gets
+    /// pushed away when refusing to join.
+    #[inline]
+    pub fn repulsion_distance(&self) -> f32 {
+        self.repulsion_distance
+    }
+
+    /// Probability of a walker sticking on contact.
+    #[inline]
+    pub fn stickiness(&self) -> f32 {
+        self.stickiness
+    }
+
+    /// Number of contacts a particle needs before it accepts a
+    /// child.
+    #[inline]
+    pub fn stubbornness(&self) -> u8 {
+        self.stubbornness
+    }
+
+    /// Whether particles are spheres with a diameter of their scale
+    /// that touch when their surfaces meet.
+    ///
+    /// Otherwise particles are points, walkers touch them within
+    /// the attraction distance and get placed `particle_spacing`
+    /// away.
+    #[inline]
+    pub fn sized_particles(&self) -> bool {
+        self.sized_particles
+    }
+
+    /// Radius of a particle with the given `scale` as far as contact
+    /// is concerned – zero unless particles are
+    /// [sized](Self::sized_particles).
+    #[inline]
+    pub fn contact_radius(&self, scale: f32) -> f32 {
+        if self.sized_particles {
+            0.5 * scale
+        } else {
+            0.0
+        }
+    }
+
+    /// Scale of the biggest particle.
+    #[inline]
+    pub fn max_scale(&self) -> f32 {
+        self.max_scale
+    }
+
+    /// Distance at which a particle of the given `scale` gets placed
+    /// from its `parent`.
+    #[inline]
+    pub fn joining_distance(&self, parent: Index, scale: f32) -> f32 {
+        if self.sized_particles {
+            self.contact_radius(self.particles[parent].1) + self.contact_radius(scale)
+        } else {
+            self.particle_spacing
+        }
+    }
+
+    /// Height above the substrate at which a particle of the given
+    /// `scale` rests: its contact radius or, unless particles are
+    /// [sized](Self::sized_particles), half the particle spacing.
+    #[inline]
+    pub fn resting_height(&self, scale: f32) -> f32 {
+        if self.sized_particles {
+            self.contact_radius(scale)
+        } else {
+            0.5 * self.particle_spacing
+        }
+    }
+
+    /// Distance of the particle furthest from the origin.
+    #[inline]
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Radius of the sphere around the origin that contains all
+    /// particles, padded by the attraction distance (and the radii of
+    /// [sized](Self::sized_particles) particles).
+    #[inline]
+    pub fn bounding_radius(&self) -> f32 {
+        self.bounding_radius
+    }
+
+    /// Center & radius of the sphere around the bounding box,
+    /// padded like the [bounding radius](Self::bounding_radius).
+    ///
+    /// Unlike [`bounding_radius()`](Self::bounding_radius) this
+    /// follows the aggregate when it grows off-center.
+    pub fn bounding_sphere(&self) -> (Point3D, f32) {
+        match self.bounding_box {
+            Some((min, max)) => (
+                0.5 * (min + max),
+                0.5 * (max - min).magnitude()
+                    + self.attraction_distance
+                    + 2.0 * self.contact_radius(self.max_scale),
+            ),
+            None => (Point3D::zeros(), 0.0),
+        }
+    }
+
+    /// Minimum & maximum corner of the box containing all particles
+    /// or `None` if the aggregate is still empty.
+    #[inline]
+    pub fn bounding_box(&self) -> Option<(Point3D, Point3D)> {
+        self.bounding_box
+    }
+
+    /// Positions and scales of all particles, in order of
+    /// aggregation.
+    #[inline]
+    pub fn particles(&self) -> &[(Point3D, f32)] {
+        &self.particles
+    }
+
+    /// The particle each particle is attached to; `None` for seeds.
+    #[inline]
+    pub fn parents(&self) -> &[Option<Index>] {
+        &self.parents
+    }
+
+    /// The seed each particle ultimately descends from, as an index
+    /// counting seeds in the order they were placed.
+    #[inline]
+    pub fn clusters(&self) -> &[u32] {
+        &self.clusters
+    }
+
+    /// The order in which particles were aggregated, counting seeds.
+    ///
+    /// This is the same as the index of a particle until some are
+    /// removed during post processing.
+    #[inline]
+    pub fn ages(&self) -> &[u32] {
+        &self.ages
+    }
+
+    /// Number of particles in each cluster.
+    pub fn cluster_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![0; self.number_of_clusters as usize];
+        self.clusters
+            .iter()
+            .for_each(|&cluster| sizes[cluster as usize] += 1);
+        sizes
+    }
+
+    /// Size of the box centered at the origin that space wraps
+    /// around in. Axes with a size of zero are not periodic.
+    #[inline]
+    pub fn periodic_box(&self) -> Option<Point3D> {
+        self.periodic_box
+    }
+
+    /// Returns `point` moved into the periodic box.
+    #[inline]
+    pub fn wrap(&self, point: &Point3D) -> Point3D {
+        match self.periodic_box {
+            Some(size) => Point3D::from_fn(|i, _| {
+                if 0.0 < size[i] {
+                    point[i] - size[i] * (point[i] / size[i] + 0.5).floor()
+                } else {
+                    point[i]
+                }
+            }),
+            None => *point,
+        }
+    }
+
+    /// Returns the shortest vector from `b` to `a`, taking the
+    /// periodic box into account.
+    #[inline]
+    pub fn difference(&self, a: &Point3D, b: &Point3D) -> Point3D {
+        let difference = a - b;
+        match self.periodic_box {
+            Some(size) => Point3D::from_fn(|i, _| {
+                if 0.0 < size[i] {
+                    difference[i] - size[i] * (difference[i] / size[i]).round()
+                } else {
+                    difference[i]
+                }
+            }),
+            None => difference,
+        }
+    }
+
+    /// Returns the point `d` away from `a` in the direction of `b`.
+    #[inline]
+    pub fn lerp_points(&self, a: &Point3D, b: &Point3D, d: f32) -> Point3D {
+        a + self.difference(b, a).normalize() * d
+    }
+
+    /// Returns the offsets to all periodic images of `point` that come
+    /// closer than `distance` to the periodic box, without `point`
+    /// itself.
+    fn images(&self, point: &Point3D, distance: f32) -> Vec<Point3D> {
+        let size = match self.periodic_box {
+            Some(size) => size,
+            None => return Vec::new(),
+        };
+        let steps = |i: usize| -> &[f32] {
+            if 0.0 < size[i] {
+                &[-1.0, 0.0, 1.0]
+            } else {
+                &[0.0]
+            }
+        };
+
+        let mut images = Vec::new();
+        for &x in steps(0) {
+            for &y in steps(1) {
+                for &z in steps(2) {
+                    if 0.0 == x && 0.0 == y && 0.0 == z {
+                        continue;
+                    }
+                    let offset = Point3D::new(x, y, z).component_mul(&size);
+                    // Distance of the image to the box.
+                    let image = point + offset;
+                    let outside = Point3D::from_fn(|i, _| {
+                        if 0.0 < size[i] {
+                            (image[i].abs() - 0.5 * size[i]).max(0.0)
+                        } else {
+                            0.0
+                        }
+                    });
+                    if outside.magnitude_squared() < distance.square() {
+                        images.push(offset);
+                    }
+                }
+            }
+        }
+        images
+    }
+
+    /// Returns the index of the nearest neighbour or `None` if the
+    /// aggregate is still empty.
+    #[inline]
+    pub fn nearest_particle(&self, point: &Point3D) -> Option<Index> {
+        let nearest = self
+            .tree
+            .nearest_neighbor(&[point.x, point.y, point.z])
+            .map(|neighbour| neighbour.data)?;
+
+        if self.periodic_box.is_none() {
+            return Some(nearest);
+        }
+
+        // Minimum image convention: a particle across the periodic
+        // boundary may be closer.
+        let mut nearest = (
+            nearest,
+            (point - self.particles[nearest].0).magnitude_squared(),
+        );
+        for offset in self.images(point, nearest.1.sqrt()) {
+            let image = point + offset;
+            if let Some(neighbour) = self.tree.nearest_neighbor(&[image.x, image.y, image.z]) {
+                let distance_squared =
+                    (image - self.particles[neighbour.data].0).magnitude_squared();
+                if distance_squared < nearest.1 {
+                    nearest = (neighbour.data, distance_squared);
+                }
+            }
+        }
+        Some(nearest.0)
+    }
+
+    /// Returns the particle whose surface is closest to that of a
+    /// walker with the given `scale` at `point` and the gap between
+    /// the two or `None` if the aggregate is still empty.
+    ///
+    /// Unless particles are [sized](Self::sized_particles) this is
+    /// the nearest particle and the distance to its center.
+    pub fn nearest_surface(&self, point: &Point3D, scale: f32) -> Option<(Index, f32)> {
+        let nearest = self.nearest_particle(point)?;
+        let distance = self
+            .difference(point, &self.particles[nearest].0)
+            .magnitude();
+        if !self.sized_particles {
+            return Some((nearest, distance));
+        }
+
+        // A bigger particle a bit further away may still be closer
+        // to the walker's surface.
+        let radius = self.contact_radius(scale);
+        let search_radius = distance + self.contact_radius(self.max_scale);
+        let mut nearest = (
+            nearest,
+            distance - self.contact_radius(self.particles[nearest].1) - radius,
+        );
+        for offset in std::iter::once(Point3D::zeros()).chain(self.images(point, search_radius)) {
+            let image = point + offset;
+            for neighbour in self
+                .tree
+                .locate_within_distance([image.x, image.y, image.z], search_radius.square())
+            {
+                let (position, scale) = self.particles[neighbour.data];
+                let gap = (image - position).magnitude() - self.contact_radius(scale) - radius;
+                if gap < nearest.1 {
+                    nearest = (neighbour.data, gap);
+                }
+            }
+        }
+        Some(nearest)
+    }
+
+    /// Returns the particles within `radius` of `point`.
+    pub fn neighbours(&self, point: &Point3D, radius: f32) -> Vec<Index> {
+        std::iter::once(Point3D::zeros())
+            .chain(self.images(point, radius))
+            .flat_map(|offset| {
+                let image = point + offset;
+                self.tree
+                    .locate_within_distance([image.x, image.y, image.z], radius.square())
+                    .map(|neighbour| neighbour.data)
+            })
+            .collect()
+    }
+
+    /// Returns the number of particles within `radius` of `point`.
+    pub fn neighbour_count(&self, point: &Point3D, radius: f32) -> usize {
+        std::iter::once(Point3D::zeros())
+            .chain(self.images(point, radius))
+            .map(|offset| {
+                let image = point + offset;
+                self.tree
+                    .locate_within_distance([image.x, image.y, image.z], radius.square())
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Removes all particles not marked in `keep` and renumbers the
+    /// remaining ones, preserving their order.
+    ///
+    /// A particle whose parent is removed gets attached to its
+    /// closest remaining ancestor or becomes a seed if there is none.
+    /// Clusters are relabeled accordingly.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let particles = std::mem::take(&mut self.particles);
+        let parents = std::mem::take(&mut self.parents);
+        let ages = std::mem::take(&mut self.ages);
+
+        self.clusters.clear();
+        self.number_of_clusters = 0;
+        self.tree = Tree::new_with_params();
+        self.max_scale = 0.0;
+        self.radius = 0.0;
+        self.bounding_radius = 0.0;
+        self.bounding_box = None;
+
+        let mut new_indices = vec![None; particles.len()];
+        for (index, &(point, scale)) in particles.iter().enumerate() {
+            if !keep[index] {
+                continue;
+            }
+            let mut parent = parents[index];
+            while let Some(ancestor) = parent {
+                if keep[ancestor] {
+                    break;
+                }
+                parent = parents[ancestor];
+            }
+
+            new_indices[index] = Some(self.particles.len());
+            self.add(
+                &point,
+                scale,
+                parent.map(|parent| new_indices[parent].unwrap()),
+            );
+            *self.ages.last_mut().unwrap() = ages[index];
+        }
+    }
+
+    /// Moves all particles to the given positions, keeping their
+    /// order, scales and parents.
+    pub fn set_positions(&mut self, positions: &[Point3D]) {
+        let keep = vec![true; self.particles.len()];
+        self.particles
+            .iter_mut()
+            .zip(positions)
+            .for_each(|(particle, position)| particle.0 = *position);
+        // Rebuilds the spatial index & bounds.
+        self.retain(&keep);
+    }
+
+    /// Add a prticle to the aggregate 'manually'.
+    ///
+    /// A particle without a parent is a seed and starts a new
+    /// cluster.
+    fn add(&mut self, point: &Point3D, scale: f32, parent: Option<Index>) {
+        let point = &self.wrap(point);
+        let index = self.particles.len();
+        self.tree
+            .insert(PointWithData::new(index, [point.x, point.y, point.z]));
+        self.particles.push((*point, scale));
+        self.parents.push(parent);
+        self.ages.push(index as u32);
+        self.clusters.push(match parent {
+            Some(parent) => self.clusters[parent],
+            None => {
+                self.number_of_clusters += 1;
+                self.number_of_clusters - 1
+            }
+        });
+        self.include(point, scale);
+    }
+
+    /// Grows the bounds to include a particle.
+    fn include(&mut self, point: &Point3D, scale: f32) {
+        self.max_scale = self.max_scale.max(scale);
+        self.radius = self.radius.max(point.magnitude());
+        // Sized particles also keep walkers as big as the biggest
+        // particle so far off their surface.
+        self.bounding_radius = self.bounding_radius.max(
+            point.magnitude()
+                + self.contact_radius(scale)
+                + self.contact_radius(self.max_scale)
+                + self.attraction_distance,
+        );
+        self.bounding_box = Some(match self.bounding_box {
+            Some((min, max)) => (min.inf(point), max.sup(point)),
+            None => (*point, *point),
+        });
+    }
+}
+
+pub struct Model {
+    config: Config,
+    aggregate: Aggregate,
+    spawn: Box<dyn SpawnStrategy>,
+    walk: Box<dyn WalkStrategy>,
+    join: Box<dyn JoinRule>,
+    placement: Box<dyn PlacementRule>,
+    symmetry: Option<Symmetry>,
+    substrate: Option<f32>,
+    drift: f32,
+    drift_direction: Point3D,
+    size_distribution: SizeDistribution,
+    abandoned_walkers: usize,
+    rng: Xoshiro256Plus,
+}
+
+impl Model {
+    /// Builds a model from `config`, failing on invalid settings.
+    pub fn new(config: &Config) -> Result<Model> {
+        let aggregation = &config.aggregation;
+        for (name, curve) in [
+            ("aggregation.spacing", &aggregation.spacing),
+            (
+                "aggregation.attraction_distance",
+                &aggregation.attraction_distance,
+            ),
+            (
+                "aggregation.repulsion_distance",
+                &aggregation.repulsion_distance,
+            ),
+            ("aggregation.stubbornness", &aggregation.stubbornness),
+            ("aggregation.stickiness", &aggregation.stickiness),
+            ("aggregation.drift", &aggregation.drift),
+            ("particle.scale", &config.particle.scale),
+        ] {
+            if let Some(curve) = curve {
+                curve.check(name)?;
+            }
+        }
+
+        if let Some(column) = config
+            .dump
+            .csv_columns
+            .iter()
+            .flatten()
+            .find(|column| !CSV_COLUMNS.contains(&column.as_str()))
+        {
+            return Err(format!(
+                "Unknown CSV column '{}'; use any of {}.",
+                column,
+                CSV_COLUMNS.join(", ")
+            )
+            .into());
+        }
+
+        let surface = &config.dump.surface;
+        if surface.voxel_size.is_some_and(|size| size <= 0.0) {
+            return Err("The surface voxel size must be positive.".into());
+        }
+        if Some("metaballs") == config.dump.geometry.as_deref() {
+            if surface.blend.is_some_and(|blend| blend <= 0.0) {
+                return Err("Metaballs need a blend radius above zero.".into());
+            }
+            if surface.iso.is_some_and(|iso| iso <= 0.0) {
+                return Err("Metaballs need an iso level above zero.".into());
+            }
+        }
+
+        let deposition = config.aggregation.deposition.as_ref();
+        let substrate = deposition.map(|d| d.substrate.unwrap_or(0.0));
+        let width = deposition.map(|d| d.width.unwrap_or(200.0));
+
+        let kill_factor = config.aggregation.spawn.kill_factor.unwrap_or(2.0);
+        let escape = match config
+            .aggregation
+            .spawn
+            .escape
+            .as_deref()
+            .unwrap_or("respawn")
+        {
+            "reinject" => Escape::Reinject,
+            _ => Escape::Respawn,
+        };
+
+        Ok(Model {
+            config: config.clone(),
+            aggregate: Aggregate::new(config),
+            spawn: match deposition {
+                Some(deposition) => Box::new(
+                    PlaneSpawn::new(
+                        substrate.unwrap(),
+                        width.unwrap(),
+                        deposition.spawn_height.unwrap_or(10.0),
+                    )
+                    .with_escape(kill_factor, escape),
+                ),
+                None => Box::new(BoundingSphereSpawn {
+                    centered_on_bounds: "bounds"
+                        == config
+                            .aggregation
+                            .spawn
+                            .center
+                            .as_deref()
+                            .unwrap_or("origin"),
+                    spawn_factor: config.aggregation.spawn.radius_factor.unwrap_or(1.0),
+                    kill_factor,
+                    escape,
+                }),
+            },
+            walk: match config
+                .aggregation
+                .walk
+                .kind
+                .as_deref()
+                .unwrap_or("isotropic")
+            {
+                "levy" => {
+                    let exponent = config.aggregation.walk.exponent.unwrap_or(1.5);
+                    if exponent <= 0.0 {
+                        return Err(format!(
+                            "The Lévy flight exponent must be positive, not {}.",
+                            exponent
+                        )
+                        .into());
+                    }
+                    Box::new(LevyFlight::new(exponent))
+                }
+                "persistent" => Box::new(PersistentWalk::new(
+                    config
+                        .aggregation
+                        .walk
+                        .turn_angle
+                        .unwrap_or(30.0)
+                        .to_radians(),
+                )),
+                _ => Box::new(IsotropicWalk),
+            },
+            join: match config
+                .aggregation
+                .join
+                .kind
+                .as_deref()
+                .unwrap_or("stubborn")
+            {
+                "neighbours" => {
+                    let probabilities = config
+                        .aggregation
+                        .join
+                        .probabilities
+                        .clone()
+                        .unwrap_or_else(|| vec![0.1, 0.5, 1.0]);
+                    if probabilities.is_empty() {
+                        return Err("At least one sticking probability is needed.".into());
+                    }
+                    Box::new(NeighbourCountJoin::new(
+                        config.aggregation.join.radius.unwrap_or_else(|| {
+                            config
+                                .aggregation
+                                .attraction_distance
+                                .as_ref()
+                                .map_or(3.0, |curve| curve.evaluate(0.0, 0.0))
+                        }),
+                        probabilities,
+                    ))
+                }
+                "noise_reduction" => Box::new(NoiseReductionJoin::new(
+                    config.aggregation.join.threshold.unwrap_or(4),
+                )),
+                _ => Box::new(StubbornJoin::default()),
+            },
+            placement: match config
+                .aggregation
+                .placement
+                .kind
+                .as_deref()
+                .unwrap_or("spacing")
+            {
+                "anisotropic" => {
+                    let strength = config.aggregation.placement.strength.unwrap_or(0.8);
+                    match config
+                        .aggregation
+                        .placement
+                        .axes
+                        .as_deref()
+                        .unwrap_or("hexagonal")
+                    {
+                        "cubic" => Box::new(AnisotropicPlacement::cubic(strength)),
+                        "custom" => {
+                            let directions = config
+                                .aggregation
+                                .placement
+                                .directions
+                                .as_deref()
+                                .unwrap_or_default()
+                                .iter()
+                                .map(|d| Point3D::new(d[0], d[1], d[2]))
+                                .collect::<Vec<_>>();
+                            if directions.is_empty() {
+                                return Err(
+                                    "Custom placement axes need a list of directions.".into()
+                                );
+                            }
+                            if directions.iter().any(|d| d.magnitude_squared() == 0.0) {
+                                return Err("Custom placement directions must not be zero.".into());
+                            }
+                            Box::new(AnisotropicPlacement::new(directions, strength))
+                        }
+                        _ => Box::new(AnisotropicPlacement::hexagonal(strength)),
+                    }
+                }
+                _ => Box::new(SpacingPlacement),
+            },
+            symmetry: {
+                let symmetry = &config.aggregation.symmetry;
+                let axis = symmetry
+                    .axis
+                    .map(|a| Point3D::new(a[0], a[1], a[2]))
+                    .unwrap_or_else(Point3D::z);
+                let folds = symmetry.folds.unwrap_or(6);
+
+                match symmetry.kind.as_deref().unwrap_or("none") {
+                    "rotation" => Some(Symmetry::rotation(&axis, folds)?),
+                    "dihedral" => Some(Symmetry::dihedral(&axis, folds)?),
+                    "mirror" => Some(Symmetry::mirror(
+                        &symmetry
+                            .planes
+                            .as_ref()
+                            .map(|planes| {
+                                planes
+                                    .iter()
+                                    .map(|n| Point3D::new(n[0], n[1], n[2]))
+                                    .collect::<Vec<_>>()
+                            })
+                            .unwrap_or_else(|| vec![Point3D::x()]),
+                    )?),
+                    "cubic" => Some(Symmetry::cubic()?),
+                    "icosahedral" => Some(Symmetry::icosahedral()?),
+                    _ => None,
+                }
+            },
+            substrate,
+            drift: 0.0,
+            drift_direction: config
+                .aggregation
+                .drift_direction
+                .map_or(-Point3D::y(), |direction| {
+                    Point3D::new(direction[0], direction[1], direction[2]).normalize()
+                }),
+            size_distribution: {
+                let deviation = config.particle.size.deviation.unwrap_or(0.2);
+                if deviation < 0.0 {
+                    return Err(format!(
+                        "The particle size deviation must be zero or positive, not {}.",
+                        deviation
+                    )
+                    .into());
+                }
+                match config.particle.size.kind.as_deref().unwrap_or("constant") {
+                    "uniform" => SizeDistribution::Uniform(deviation),
+                    "normal" => SizeDistribution::Normal(deviation),
+                    "log_normal" => SizeDistribution::LogNormal(deviation),
+                    _ => SizeDistribution::Constant,
+                }
+            },
+            abandoned_walkers: 0,
+            rng: Xoshiro256Plus::seed_from_u64(config.aggregation.random_seed.unwrap_or(42)),
+        })
+    }
+
+    /// Replaces how new particles are spawned & when they are
+    /// considered lost.
+    pub fn set_spawn_strategy(&mut self, spawn: Box<dyn SpawnStrategy>) {
+        self.spawn = spawn;
+    }
+
+    /// Replaces how particles move during their random walk.
+    pub fn set_walk_strategy(&mut self, walk: Box<dyn WalkStrategy>) {
+        self.walk = walk;
+    }
+
+    /// Replaces the rule deciding whether a particle sticks on
+    /// contact.
+    pub fn set_join_rule(&mut self, join: Box<dyn JoinRule>) {
+        self.join = join;
+    }
+
+    /// Replaces how a sticking particle is positioned relative to
+    /// its parent.
+    pub fn set_placement_rule(&mut self, placement: Box<dyn PlacementRule>) {
+        self.placement = placement;
+    }
+
+    /// Replicates every particle sticking to the aggregate into all
+    /// its symmetric copies, or stops doing so for `None`.
+    pub fn set_symmetry(&mut self, symmetry: Option<Symmetry>) {
+        self.symmetry = symmetry;
+    }
+
+    /// The aggregate grown so far.
+    pub fn aggregate(&self) -> &Aggregate {
+        &self.aggregate
+    }
+
+    /// Number of walkers given up on because they took more than
+    /// `walker_steps` steps.
+    pub fn abandoned_walkers(&self) -> usize {
+        self.abandoned_walkers
+    }
+
+    /// Grows the aggregate until the particle count or one of the
+    /// `[aggregation.limits]` is reached and returns which it was.
+    ///
+    /// Fails if there is nothing to stick to: no seed particles and
+    /// no `[aggregation.deposition]` substrate.
+    pub fn run(&mut self) -> Result<StopReason> {
+        let start_time = Instant::now();
+        let limits = self.config.aggregation.limits.clone();
+        let walker_steps = limits.walker_steps.unwrap_or(u64::MAX);
+        let deadline = limits
+            .time
+            .map(|time| start_time + Duration::from_secs_f32(time));
+        let max_abandoned_in_a_row = limits.abandoned_walkers.unwrap_or(1000);
+        let mut abandoned_in_a_row = 0;
+
         let mut number_of_particles = self.config.aggregation.particles.unwrap_or(1000);
 
         let progress_bar = if self.config.aggregation.show_progress.unwrap_or(true) {
@@ -104,17 +978,27 @@ impl Model {
                 .progress_chars("█▉▊▋▌▍▎▏  "),
         );
 
-        let scale = self.config.particle.scale.unwrap_or([2.0f32; 2]);
-
-        match self
+        let scale = self
             .config
-            .aggregation
-            .start_shape
-            .shape
-            .as_ref()
-            .unwrap_or(&"point".to_string())
-            .as_str()
-        {
+            .particle
+            .scale
+            .clone()
+            .unwrap_or(Curve::Range([2.0; 2]));
+        self.update_parameters(0.0);
+
+        // Particles read from a file replace the start shape.
+        let shape = if self.aggregate.particles.is_empty() {
+            self.config
+                .aggregation
+                .start_shape
+                .shape
+                .as_deref()
+                .unwrap_or("point")
+        } else {
+            "none"
+        };
+
+        match shape {
             "ring" => {
                 let radius = self
                     .config
@@ -130,38 +1014,142 @@ impl Model {
                     let angle = (i as f32 / particles as f32) * std::f32::consts::TAU;
                     let x = angle.cos() * radius;
                     let y = angle.sin() * radius;
-                    self.add(&Point3D::new(x, y, 0.0), scale[0]);
+                    self.aggregate
+                        .add(&Point3D::new(x, y, 0.0), scale.evaluate(0.0, 0.0), None);
                     progress_bar.inc(1);
                 }
-                number_of_particles -= particles;
+                number_of_particles = number_of_particles.saturating_sub(particles);
+            }
+            "none" => (),
+            "points" => {
+                let points = self
+                    .config
+                    .aggregation
+                    .start_shape
+                    .points
+                    .clone()
+                    .unwrap_or_else(|| vec![[0.0; 3]]);
+
+                for point in &points {
+                    self.aggregate.add(
+                        &Point3D::new(point[0], point[1], point[2]),
+                        scale.evaluate(0.0, 0.0),
+                        None,
+                    );
+                    progress_bar.inc(1);
+                }
+                number_of_particles = number_of_particles.saturating_sub(points.len() as u32);
             }
             _ => {
                 // Single seed point.
-                self.add(&dla::Point3D::new(0.0, 0.0, 0.0), scale[0]);
-                number_of_particles -= 1;
+                self.aggregate
+                    .add(&Point3D::new(0.0, 0.0, 0.0), scale.evaluate(0.0, 0.0), None);
+                number_of_particles = number_of_particles.saturating_sub(1);
             }
         };
 
+        // Without seeds walkers could only stick to the substrate.
+        if self.aggregate.particles.is_empty() && self.substrate.is_none() {
+            progress_bar.finish_and_clear();
+            return Err(
+                "Nothing to grow on: no seed particles and no deposition substrate.".into(),
+            );
+        }
+
         // Run diffusion-limited aggregation.
-        for p in 0..number_of_particles {
-            self.diffuse_particle(lerp(
-                scale[0],
-                scale[1],
-                p as f32 / number_of_particles as f32,
-            ));
+        let seeds = self.aggregate.particles.len();
+        // Symmetric copies count towards the total so we loop until
+        // we have enough particles instead of once per particle.
+        let stop_reason = loop {
+            if self.aggregate.particles.len() - seeds >= number_of_particles as usize {
+                break StopReason::Particles;
+            }
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                break StopReason::Time;
+            }
+            if max_abandoned_in_a_row <= abandoned_in_a_row {
+                break StopReason::Abandoned;
+            }
+            if limits
+                .radius
+                .is_some_and(|radius| radius <= self.aggregate.radius)
+            {
+                break StopReason::Radius;
+            }
+            if let (Some(reach), Some((min, max))) = (limits.reach, self.aggregate.bounding_box) {
+                // axes with a reach of 0 are ignored
+                if (0..3).any(|axis| 0.0 < reach[axis] && reach[axis] <= max[axis].max(-min[axis]))
+                {
+                    break StopReason::Reach;
+                }
+            }
 
-            let spacing = self.config.aggregation.spacing.unwrap_or([1.0f32; 2]);
+            let fraction =
+                (self.aggregate.particles.len() - seeds) as f32 / number_of_particles as f32;
+            self.update_parameters(fraction);
 
-            self.particle_spacing = lerp(
-                spacing[0],
-                spacing[1],
-                p as f32 / number_of_particles as f32,
+            let particle_scale = self.size_distribution.sample(
+                scale.evaluate(fraction, self.aggregate.radius),
+                &mut self.rng,
             );
+            match self.diffuse_particle(particle_scale, walker_steps, deadline) {
+                Diffusion::Stuck => abandoned_in_a_row = 0,
+                Diffusion::Abandoned => {
+                    self.abandoned_walkers += 1;
+                    abandoned_in_a_row += 1;
+                }
+                Diffusion::OutOfTime => break StopReason::Time,
+            }
 
-            progress_bar.inc(1);
-        }
+            progress_bar.set_position(self.aggregate.particles.len() as u64);
+        };
 
         progress_bar.finish();
+
+        Ok(stop_reason)
+    }
+
+    /// Applies the `[post_process]` steps from the config to the
+    /// grown aggregate and returns how many particles were pruned.
+    pub fn post_process(&mut self) -> usize {
+        let count = self.aggregate.particles.len();
+        let post_process = &self.config.post_process;
+
+        if let Some(min_length) = post_process.min_branch_length {
+            let keep = prune_short_branches(&self.aggregate.parents, min_length as usize);
+            self.aggregate.retain(&keep);
+        }
+        if let Some(min_order) = post_process.min_strahler_order {
+            let keep = prune_by_order(&self.aggregate.parents, min_order);
+            self.aggregate.retain(&keep);
+        }
+        for _ in 0..post_process.smoothing_iterations.unwrap_or(0) {
+            let positions = smooth_branches(&self.aggregate, post_process.smoothing.unwrap_or(0.5));
+            self.aggregate.set_positions(&positions);
+        }
+
+        count - self.aggregate.particles.len()
+    }
+
+    /// Evaluates the parameter curves for the given `fraction` of
+    /// particles placed and the current radius of the aggregate.
+    fn update_parameters(&mut self, fraction: f32) {
+        let radius = self.aggregate.radius;
+        let evaluate = |curve: &Option<Curve>, default: f32| {
+            curve
+                .as_ref()
+                .map_or(default, |curve| curve.evaluate(fraction, radius))
+        };
+        let aggregation = &self.config.aggregation;
+
+        self.aggregate.particle_spacing = evaluate(&aggregation.spacing, 1.0);
+        self.aggregate.attraction_distance = evaluate(&aggregation.attraction_distance, 3.0);
+        self.aggregate.repulsion_distance = evaluate(&aggregation.repulsion_distance, 1.0);
+        self.aggregate.stickiness = evaluate(&aggregation.stickiness, 1.0);
+        self.aggregate.stubbornness = evaluate(&aggregation.stubbornness, 0.0)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+        self.drift = evaluate(&aggregation.drift, 0.0);
     }
 
     /// Renders the scene via 3Delight|NSI.
@@ -189,168 +1177,1008 @@ impl Model {
         self.output_scene_nsi(&c);
     }
 
-    #[allow(unused_must_use)]
-    pub fn write_ply(&self, path: &Path) {
-        // Create a ply object.
-        let mut ply = {
-            let mut ply = Ply::<DefaultElement>::new();
-            ply.header.encoding = Encoding::Ascii;
-            ply.header
-                .comments
-                .push("Reaction limited diffusion".to_string());
+    /// Writes the particles as a Stanford PLY file.
+    ///
+    /// Besides the positions this writes the scale, radius, parent
+    /// (-1 for seeds), cluster, age and depth of each particle in the
+    /// `vertex` element and the parent links as an `edge` element,
+    /// unless that is disabled in the config and `dump.geometry` asks
+    /// for particles only. Data is streamed to the file as it is
+    /// encoded.
+    ///
+    /// Surface geometry, `"tubes"` or `"metaballs"`, is written as a
+    /// mesh with a `face` element instead.
+    pub fn write_ply(&self, path: &Path) -> Result<()> {
+        let encoding = match self
+            .config
+            .dump
+            .encoding
+            .as_deref()
+            .unwrap_or("binary_little_endian")
+        {
+            "ascii" => Encoding::Ascii,
+            _ => Encoding::BinaryLittleEndian,
+        };
+        if let Some(mesh) = self.surface() {
+            let mut out = BufWriter::new(File::create(path)?);
+            mesh.write_ply(&mut out, encoding)?;
+            out.flush()?;
+            return Ok(());
+        }
 
-            let mut point_element = ElementDef::new("point".to_string());
+        let write_edges = self.config.dump.edges.unwrap_or(true)
+            || "particles" != self.config.dump.geometry.as_deref().unwrap_or("particles");
+
+        let particles = &self.aggregate.particles;
+        let parents = &self.aggregate.parents;
+        let depths = depths(parents);
+        let edges = parents
+            .iter()
+            .enumerate()
+            .filter_map(|(child, parent)| parent.map(|parent| (parent, child)));
+
+        let mut header = Header::new();
+        header.encoding = encoding;
+        header
+            .comments
+            .push("Reaction limited diffusion".to_string());
+
+        let property = |name: &str, scalar_type| {
+            PropertyDef::new(name.to_string(), PropertyType::Scalar(scalar_type))
+        };
 
-            point_element.properties.add(PropertyDef::new(
-                "x".to_string(),
-                PropertyType::Scalar(ScalarType::Float),
-            ));
-            point_element.properties.add(PropertyDef::new(
-                "y".to_string(),
-                PropertyType::Scalar(ScalarType::Float),
-            ));
-            point_element.properties.add(PropertyDef::new(
-                "z".to_string(),
-                PropertyType::Scalar(ScalarType::Float),
-            ));
-            ply.header.elements.add(point_element);
+        let mut vertex_element = ElementDef::new("vertex".to_string());
+        vertex_element.count = particles.len();
+        for name in &["x", "y", "z", "scale", "radius"] {
+            vertex_element
+                .properties
+                .add(property(name, ScalarType::Float));
+        }
+        vertex_element
+            .properties
+            .add(property("parent", ScalarType::Int));
+        for name in &["cluster", "age", "depth"] {
+            vertex_element
+                .properties
+                .add(property(name, ScalarType::UInt));
+        }
+        header.elements.add(vertex_element);
 
-            // Add data
-            let mut points = Vec::new();
+        if write_edges {
+            let mut edge_element = ElementDef::new("edge".to_string());
+            edge_element.count = edges.clone().count();
+            for name in &["vertex1", "vertex2"] {
+                edge_element.properties.add(property(name, ScalarType::Int));
+            }
+            header.elements.add(edge_element);
+        }
 
-            for particle in &self.particles {
-                let mut point = DefaultElement::new();
-                point.insert("x".to_string(), Property::Float(particle.0.x));
-                point.insert("y".to_string(), Property::Float(particle.0.y));
-                point.insert("z".to_string(), Property::Float(particle.0.z));
-                points.push(point);
+        let mut out = BufWriter::new(File::create(path)?);
+        Writer::<DefaultElement>::new().write_header(&mut out, &header)?;
+
+        let vertices = particles
+            .iter()
+            .zip(parents)
+            .zip(&self.aggregate.clusters)
+            .zip(&self.aggregate.ages)
+            .zip(&depths);
+
+        if Encoding::Ascii == encoding {
+            for (((((point, scale), parent), cluster), age), depth) in vertices {
+                writeln!(
+                    out,
+                    "{} {} {} {} {} {} {} {} {}",
+                    point.x,
+                    point.y,
+                    point.z,
+                    scale,
+                    0.5 * scale,
+                    parent.map_or(-1, |parent| parent as i32),
+                    cluster,
+                    age,
+                    depth
+                )?;
+            }
+            if write_edges {
+                for (parent, child) in edges {
+                    writeln!(out, "{} {}", parent, child)?;
+                }
+            }
+        } else {
+            for (((((point, scale), parent), cluster), age), depth) in vertices {
+                for value in &[point.x, point.y, point.z, *scale, 0.5 * scale] {
+                    out.write_all(&value.to_le_bytes())?;
+                }
+                out.write_all(&parent.map_or(-1, |parent| parent as i32).to_le_bytes())?;
+                for value in &[*cluster, *age, *depth] {
+                    out.write_all(&value.to_le_bytes())?;
+                }
+            }
+            if write_edges {
+                for (parent, child) in edges {
+                    out.write_all(&(parent as i32).to_le_bytes())?;
+                    out.write_all(&(child as i32).to_le_bytes())?;
+                }
+            }
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes the growth tree as a Wavefront OBJ file.
+    ///
+    /// Each particle becomes a vertex with its radius as the `w`
+    /// component. Depending on `dump.geometry` the parent links are
+    /// written as one `l` record each (`"lines"`), merged into one
+    /// per unbranched chain (`"polylines"`) or left out
+    /// (`"particles"`).
+    ///
+    /// With `"tubes"` or `"metaballs"` the file instead holds the
+    /// mesh built by [`surface()`](Self::surface).
+    pub fn write_obj(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "# Reaction limited diffusion")?;
+
+        if let Some(mesh) = self.surface() {
+            mesh.write_obj(&mut out)?;
+            out.flush()?;
+            return Ok(());
+        }
+
+        for (point, scale) in &self.aggregate.particles {
+            writeln!(out, "v {} {} {} {}", point.x, point.y, point.z, 0.5 * scale)?;
+        }
+
+        let parents = &self.aggregate.parents;
+        match self.config.dump.geometry.as_deref().unwrap_or("particles") {
+            "lines" => {
+                for (child, parent) in parents.iter().enumerate() {
+                    if let Some(parent) = parent {
+                        // OBJ indices start at one.
+                        writeln!(out, "l {} {}", parent + 1, child + 1)?;
+                    }
+                }
+            }
+            "polylines" => {
+                for polyline in polylines(parents) {
+                    write!(out, "l")?;
+                    for index in polyline {
+                        write!(out, " {}", index + 1)?;
+                    }
+                    writeln!(out)?;
+                }
             }
+            _ => (),
+        }
+
+        out.flush()?;
+        Ok(())
+    }
 
-            ply.payload.insert("point".to_string(), points);
+    /// Writes the particles as comma separated values.
+    ///
+    /// After a header, each row is `id,parent,x,y,z` with -1 as the
+    /// parent of seeds, like the classic DLA tools write. Columns
+    /// listed in `dump.csv_columns` – `scale`, `radius`, `cluster`,
+    /// `age` and/or `depth` – are appended.
+    pub fn write_csv(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let extra_columns = self.config.dump.csv_columns.clone().unwrap_or_default();
+        let depths = depths(&self.aggregate.parents);
+
+        write!(out, "id,parent,x,y,z")?;
+        for column in &extra_columns {
+            write!(out, ",{}", column)?;
+        }
+        writeln!(out)?;
+
+        for (index, ((point, scale), parent)) in self
+            .aggregate
+            .particles
+            .iter()
+            .zip(&self.aggregate.parents)
+            .enumerate()
+        {
+            write!(
+                out,
+                "{},{},{},{},{}",
+                index,
+                parent.map_or(-1, |parent| parent as i64),
+                point.x,
+                point.y,
+                point.z
+            )?;
+            for column in &extra_columns {
+                match column.as_str() {
+                    "scale" => write!(out, ",{}", scale)?,
+                    "radius" => write!(out, ",{}", 0.5 * scale)?,
+                    "cluster" => write!(out, ",{}", self.aggregate.clusters[index])?,
+                    "age" => write!(out, ",{}", self.aggregate.ages[index])?,
+                    "depth" => write!(out, ",{}", depths[index])?,
+                    // Rejected in Model::new().
+                    _ => unreachable!(),
+                }
+            }
+            writeln!(out)?;
+        }
 
-            // only `write_ply` calls this by itself, for all other
-            // methods the client is responsible to make the
-            // data structure consistent. We do it here for
-            // demonstration purpose.
-            ply.make_consistent().unwrap();
+        out.flush()?;
+        Ok(())
+    }
 
-            ply
+    /// Writes the particles as Houdini JSON geometry.
+    ///
+    /// Points carry `P`, `pscale` (the particle scale), `parent` (-1
+    /// for seeds), `age` & `depth` attributes. The parent links are
+    /// open polygons, one per unbranched chain.
+    pub fn write_geo(&self, path: &Path) -> Result<()> {
+        let particles = &self.aggregate.particles;
+        let polylines = polylines(&self.aggregate.parents);
+        let vertex_count = polylines.iter().map(Vec::len).sum::<usize>();
+
+        let attribute = |name: &str, storage: &str, size: usize, values: serde_json::Value| {
+            let default_storage = if "int32" == storage {
+                "int32"
+            } else {
+                "fpreal64"
+            };
+            let definition = json!(["scope", "public", "type", "numeric", "name", name]);
+            let defaults = json!(["size", 1, "storage", default_storage, "values", [0]]);
+            json!([
+                definition,
+                ["size", size, "storage", storage, "defaults", defaults, "values", values]
+            ])
+        };
+        let integers = |name: &str, values: Vec<i64>| {
+            attribute(
+                name,
+                "int32",
+                1,
+                json!(["size", 1, "storage", "int32", "arrays", [values]]),
+            )
         };
 
-        let mut buffer = File::create(&path).unwrap();
+        let positions = particles
+            .iter()
+            .map(|(point, _)| [point.x, point.y, point.z])
+            .collect::<Vec<_>>();
+        let scales = particles
+            .iter()
+            .map(|(_, scale)| *scale)
+            .collect::<Vec<_>>();
+        let parents = self
+            .aggregate
+            .parents
+            .iter()
+            .map(|parent| parent.map_or(-1, |parent| parent as i64))
+            .collect();
+        let ages = self.aggregate.ages.iter().map(|&age| age as i64).collect();
+        let depths = depths(&self.aggregate.parents)
+            .into_iter()
+            .map(|depth| depth as i64)
+            .collect();
+
+        // Vertices are numbered along the polylines.
+        let mut next_vertex = 0;
+        let primitives = polylines
+            .iter()
+            .map(|polyline| {
+                let vertices = (next_vertex..next_vertex + polyline.len()).collect::<Vec<_>>();
+                next_vertex += polyline.len();
+                json!([["type", "Poly"], ["vertex", vertices, "closed", false]])
+            })
+            .collect::<Vec<_>>();
+
+        let geometry = json!([
+            "fileversion", "18.0",
+            "hasindex", false,
+            "pointcount", particles.len(),
+            "vertexcount", vertex_count,
+            "primitivecount", polylines.len(),
+            "info", { "software": "rdla" },
+            "topology", [
+                "pointref", ["indices", polylines.concat()]
+            ],
+            "attributes", [
+                "pointattributes", [
+                    attribute(
+                        "P",
+                        "fpreal32",
+                        3,
+                        json!(["size", 3, "storage", "fpreal32", "tuples", positions]),
+                    ),
+                    attribute(
+                        "pscale",
+                        "fpreal32",
+                        1,
+                        json!(["size", 1, "storage", "fpreal32", "arrays", [scales]]),
+                    ),
+                    integers("parent", parents),
+                    integers("age", ages),
+                    integers("depth", depths),
+                ]
+            ],
+            "primitives", primitives,
+        ]);
 
-        let w = Writer::new();
-        w.write_ply(&mut buffer, &mut ply);
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut out, &geometry).map_err(std::io::Error::from)?;
+        out.flush()?;
+        Ok(())
     }
 
-    /// Add a prticle to the model 'manually'.
-    fn add(&mut self, point: &Point3D, scale: f32) {
-        let index = self.particles.len();
-        self.tree
-            .insert(PointWithData::new(index, [point.x, point.y, point.z]));
-        self.particles.push((*point, scale));
-        self.join_attempts.push(0);
-        self.bounding_radius = self
-            .bounding_radius
-            .max(point.magnitude() + self.attraction_distance);
+    /// Returns the mesh `dump.geometry` asks for, if it is a surface:
+    /// `"tubes"` or `"metaballs"`.
+    pub fn surface(&self) -> Option<Mesh> {
+        match self.config.dump.geometry.as_deref().unwrap_or("particles") {
+            "tubes" => Some(self.tubes()),
+            "metaballs" => Some(self.metaballs()),
+            _ => None,
+        }
     }
 
-    /// Diffuses one new particle and adds it to the model.
-    fn diffuse_particle(&mut self, scale: f32) {
-        // compute particle starting location
-        let particle: &mut Point3D = &mut self.random_particle();
+    /// Builds a closed tube mesh around the growth tree as configured
+    /// in `dump.surface`.
+    ///
+    /// The tube radius is half the particle scale or, with `radius =
+    /// "pipe_model"`, grows with the number of descendants.
+    pub fn tubes(&self) -> Mesh {
+        let surface = &self.config.dump.surface;
+        let radii = match surface.radius.as_deref().unwrap_or("scale") {
+            "pipe_model" => pipe_model_radii(&self.aggregate, surface.pipe_exponent.unwrap_or(2.0)),
+            _ => self
+                .aggregate
+                .particles
+                .iter()
+                .map(|(_, scale)| 0.5 * scale)
+                .collect(),
+        };
+        let min_radius = radii.iter().cloned().fold(f32::INFINITY, f32::min);
 
-        // do the random walk
-        loop {
-            // get distance to nearest other particle
-            let parent = self.nearest_particle(&particle);
-            let distance_squared = (*particle - self.particles[parent].0).magnitude_squared();
+        tubes(
+            &self.aggregate,
+            &radii,
+            surface.blend.unwrap_or(min_radius),
+            surface.voxel_size.unwrap_or(0.5 * min_radius),
+        )
+    }
 
-            // check if close enough to join
-            if distance_squared < self.attraction_distance.square() {
-                if !self.should_join(parent) {
-                    // push particle away a bit
-                    *particle = lerp_points(
-                        &self.particles[parent].0,
-                        &particle,
-                        self.attraction_distance + self.repulsion_distance,
-                    );
-                    continue;
-                }
+    /// Builds a blobby surface around all particles as configured in
+    /// `dump.surface`.
+    pub fn metaballs(&self) -> Mesh {
+        let surface = &self.config.dump.surface;
+        let min_radius = 0.5
+            * self
+                .aggregate
+                .particles
+                .iter()
+                .map(|(_, scale)| *scale)
+                .fold(f32::INFINITY, f32::min);
+
+        metaballs(
+            &self.aggregate,
+            surface.blend.unwrap_or(min_radius),
+            surface.iso.unwrap_or(1.0),
+            surface.voxel_size.unwrap_or(0.5 * min_radius),
+        )
+    }
+
+    /// Writes the particles as a glTF 2.0 `.gltf` file with a `.bin`
+    /// sidecar or as a single binary `.glb` file.
+    ///
+    /// Particles are instances of `particle.instance_geo` or a sphere
+    /// using `EXT_mesh_gpu_instancing`, scaled by their scale. With
+    /// `dump.instancing` off they become a point cloud instead.
+    pub fn write_gltf(&self, path: &Path) -> Result<()> {
+        let material = &self.config.material;
+        let mut gltf = Gltf::new();
+        let material = gltf.add_material(
+            material.color.unwrap_or([1.0f32, 0.6, 0.3]),
+            material.metallic.unwrap_or(0.),
+            material.roughness.unwrap_or(0.),
+        );
 
-                // adjust particle position in relation to its parent
-                *particle = self.place_particle(&particle, parent);
+        let (positions, scales): (Vec<_>, Vec<_>) =
+            self.aggregate.particles.iter().cloned().unzip();
+
+        if self.config.dump.instancing.unwrap_or(true) {
+            let instance = self
+                .config
+                .particle
+                .instance_geo
+                .as_ref()
+                .map(Path::new)
+                .filter(|path| path.exists())
+                .and_then(|path| {
+                    tobj::load_obj(
+                        path,
+                        &tobj::LoadOptions {
+                            single_index: true,
+                            triangulate: true,
+                            ..Default::default()
+                        },
+                    )
+                    .map_err(|e| eprintln!("Error loading '{}': {}", path.display(), e))
+                    .ok()
+                })
+                .map(|(models, _materials)| {
+                    models
+                        .into_iter()
+                        .map(|model| Primitive {
+                            positions: model.mesh.positions,
+                            normals: model.mesh.normals,
+                            indices: model.mesh.indices,
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_else(|| vec![Primitive::sphere(24, 12)]);
+
+            let mesh = gltf.add_mesh(&instance, material);
+            gltf.add_instances(mesh, &positions, &scales);
+        } else {
+            gltf.add_points(&positions, &scales, material);
+        }
 
-                // add the point
-                self.add(&particle, scale); //, parent);
-                break;
-            }
+        gltf.write(path)?;
+        Ok(())
+    }
 
-            // move randomly
-            let move_magnitude = self
-                .repulsion_distance
-                .max(distance_squared.sqrt() - self.attraction_distance);
-            *particle += move_magnitude * self.random_point_on_unit_sphere();
+    /// Writes the particles as an ASCII USD file with a
+    /// `PointInstancer`.
+    ///
+    /// The prototype is a sphere or, if it exists, the
+    /// `particle.instance_geo` OBJ. Each instance is scaled by its
+    /// particle's scale and carries the parent (-1 for seeds),
+    /// cluster, age and depth as primvars.
+    pub fn write_usda(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let particles = &self.aggregate.particles;
+        let color = self.config.material.color.unwrap_or([1.0f32, 0.6, 0.3]);
+
+        writeln!(out, "#usda 1.0")?;
+        writeln!(out, "(")?;
+        writeln!(out, "    defaultPrim = \"Aggregate\"")?;
+        writeln!(out, "    doc = \"Reaction limited diffusion\"")?;
+        writeln!(out, "    upAxis = \"Y\"")?;
+        writeln!(out, ")")?;
+        writeln!(out)?;
+        writeln!(out, "def Xform \"Aggregate\"")?;
+        writeln!(out, "{{")?;
+        writeln!(out, "    def PointInstancer \"Particles\"")?;
+        writeln!(out, "    {{")?;
+
+        let point = |p: &Point3D| format!("({}, {}, {})", p.x, p.y, p.z);
+
+        write_usd_array(&mut out, 8, "point3f[] positions", particles.iter(), |p| {
+            point(&p.0)
+        })?;
+        write_usd_array(&mut out, 8, "float3[] scales", particles.iter(), |p| {
+            point(&Point3D::repeat(p.1))
+        })?;
+        write_usd_array(&mut out, 8, "int[] protoIndices", particles.iter(), |_| {
+            "0".to_string()
+        })?;
+        writeln!(
+            out,
+            "        rel prototypes = </Aggregate/Particles/Prototypes/Particle>"
+        )?;
+
+        let depths = depths(&self.aggregate.parents);
+        let primvars: [(&str, Vec<i64>); 4] = [
+            (
+                "parent",
+                self.aggregate
+                    .parents
+                    .iter()
+                    .map(|parent| parent.map_or(-1, |parent| parent as i64))
+                    .collect(),
+            ),
+            (
+                "cluster",
+                self.aggregate.clusters.iter().map(|&c| c as i64).collect(),
+            ),
+            (
+                "age",
+                self.aggregate.ages.iter().map(|&age| age as i64).collect(),
+            ),
+            ("depth", depths.iter().map(|&depth| depth as i64).collect()),
+        ];
+        for (name, values) in &primvars {
+            write_usd_values(
+                &mut out,
+                8,
+                &format!("int[] primvars:{}", name),
+                values.iter(),
+                |value| value.to_string(),
+            )?;
+            // One value per instance.
+            writeln!(out, " (")?;
+            writeln!(out, "            interpolation = \"vertex\"")?;
+            writeln!(out, "        )")?;
+        }
+        writeln!(out)?;
 
-            // reset to a new random particle if is too far away
-            if self.out_of_bounds(&particle) {
-                *particle = self.random_particle();
+        // Prototypes below the instancer are not drawn by themselves.
+        writeln!(out, "        def Scope \"Prototypes\"")?;
+        writeln!(out, "        {{")?;
+
+        let models = self
+            .config
+            .particle
+            .instance_geo
+            .as_ref()
+            .map(Path::new)
+            .filter(|path| path.exists())
+            .and_then(|path| {
+                tobj::load_obj(path, &tobj::LoadOptions::default())
+                    .map_err(|e| eprintln!("Error loading '{}': {}", path.display(), e))
+                    .ok()
+            })
+            .map(|(models, _materials)| models);
+
+        let display_color = format!(
+            "color3f[] primvars:displayColor = [({}, {}, {})]",
+            color[0], color[1], color[2]
+        );
+        match models {
+            Some(models) => {
+                writeln!(out, "            def Xform \"Particle\"")?;
+                writeln!(out, "            {{")?;
+                for (i, model) in models.iter().enumerate() {
+                    let mesh = &model.mesh;
+                    // Prim names must be identifiers.
+                    let mut name = model
+                        .name
+                        .chars()
+                        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                        .collect::<String>();
+                    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                        name = format!("Mesh{}_{}", i, name);
+                    }
+                    let face_arities = if mesh.face_arities.is_empty() {
+                        vec![3; mesh.indices.len() / 3]
+                    } else {
+                        mesh.face_arities.clone()
+                    };
+
+                    writeln!(out, "                def Mesh \"{}\"", name)?;
+                    writeln!(out, "                {{")?;
+                    write_usd_array(
+                        &mut out,
+                        20,
+                        "point3f[] points",
+                        mesh.positions.chunks(3),
+                        |p| format!("({}, {}, {})", p[0], p[1], p[2]),
+                    )?;
+                    write_usd_array(
+                        &mut out,
+                        20,
+                        "int[] faceVertexCounts",
+                        face_arities.iter(),
+                        |count| count.to_string(),
+                    )?;
+                    write_usd_array(
+                        &mut out,
+                        20,
+                        "int[] faceVertexIndices",
+                        mesh.indices.iter(),
+                        |index| index.to_string(),
+                    )?;
+                    writeln!(
+                        out,
+                        "                    uniform token subdivisionScheme = \"{}\"",
+                        if self.config.particle.subdivision.unwrap_or(false) {
+                            "catmullClark"
+                        } else {
+                            "none"
+                        }
+                    )?;
+                    writeln!(out, "                    {}", display_color)?;
+                    writeln!(out, "                }}")?;
+                }
+                writeln!(out, "            }}")?;
+            }
+            None => {
+                writeln!(out, "            def Sphere \"Particle\"")?;
+                writeln!(out, "            {{")?;
+                writeln!(out, "                double radius = 0.5")?;
+                writeln!(out, "                {}", display_color)?;
+                writeln!(out, "            }}")?;
             }
         }
+
+        writeln!(out, "        }}")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+        out.flush()?;
+        Ok(())
     }
 
-    /// Returns the index of the nearest neighbour.
-    #[inline]
-    fn nearest_particle(&self, point: &Point3D) -> Index {
-        self.tree
-            .nearest_neighbor(&[point.x, point.y, point.z])
-            .unwrap()
-            .data
+    /// Splats the particles into a voxel grid as configured in
+    /// `dump.voxels`.
+    pub fn volume(&self) -> Volume {
+        let voxels = &self.config.dump.voxels;
+        Volume::splat(
+            &self.aggregate,
+            voxels.resolution.unwrap_or(128) as _,
+            voxels
+                .kernel_width
+                .unwrap_or(2.0 * self.aggregate.max_scale()),
+            voxels
+                .channels
+                .as_deref()
+                .unwrap_or(&["density".to_string()]),
+        )
     }
 
-    /// Returns a random, uniformly distributed point inside the unit
-    /// sphere.
-    fn random_point_on_unit_sphere(&mut self) -> Point3D {
-        //let u: ;
-        let v: [f32; 3] = UnitSphere.sample(&mut self.rng);
-        Point3D::new(v[0], v[1], v[2])
+    /// Writes a voxel grid of the particles as an NRRD volume.
+    pub fn write_nrrd(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.volume().write_nrrd(&mut out)?;
+        out.flush()?;
+        Ok(())
     }
 
-    /// Returns a random point to start a new particle.
-    #[inline]
-    fn random_particle(&mut self) -> Point3D {
-        self.random_point_on_unit_sphere() * self.bounding_radius
+    /// Replaces the aggregate with the particles from a Stanford PLY
+    /// file as written by [`write_ply()`](Self::write_ply).
+    ///
+    /// Only the positions are required. Scales are taken from a
+    /// `scale` or `radius` property and default to the first value of
+    /// `particle.scale`. Parents are read from a `parent`
+    /// property or, failing that, from an `edge` element. The next
+    /// [`run()`](Self::run) continues growing from the loaded
+    /// particles.
+    pub fn read_ply(&mut self, path: &Path) -> Result<()> {
+        let ply =
+            Parser::<DefaultElement>::new().read_ply(&mut BufReader::new(File::open(path)?))?;
+
+        let vertices = ply
+            .payload
+            .get("vertex")
+            .or_else(|| ply.payload.get("point"))
+            .ok_or_else(|| format!("No vertex element in '{}'.", path.display()))?;
+
+        let default_scale = self.default_scale();
+        let mut particles = Vec::with_capacity(vertices.len());
+        for vertex in vertices {
+            let coordinate = |name| {
+                vertex
+                    .get(name)
+                    .and_then(property_value)
+                    .ok_or_else(|| format!("Vertex without '{}' in '{}'.", name, path.display()))
+            };
+            particles.push((
+                Point3D::new(
+                    coordinate("x")? as f32,
+                    coordinate("y")? as f32,
+                    coordinate("z")? as f32,
+                ),
+                vertex
+                    .get("scale")
+                    .and_then(property_value)
+                    .or_else(|| {
+                        vertex
+                            .get("radius")
+                            .and_then(property_value)
+                            .map(|radius| 2.0 * radius)
+                    })
+                    .map_or(default_scale, |scale| scale as f32),
+            ));
+        }
+
+        let index = |value: f64| {
+            if 0.0 <= value && (value as usize) < particles.len() {
+                Some(value as usize)
+            } else {
+                None
+            }
+        };
+        let mut parents = vec![None; particles.len()];
+        if vertices.iter().any(|vertex| vertex.contains_key("parent")) {
+            for (parent, vertex) in parents.iter_mut().zip(vertices) {
+                *parent = vertex
+                    .get("parent")
+                    .and_then(property_value)
+                    .and_then(index);
+            }
+        } else if let Some(edges) = ply.payload.get("edge") {
+            for edge in edges {
+                let vertex = |name| edge.get(name).and_then(property_value).and_then(index);
+                if let (Some(parent), Some(child)) = (vertex("vertex1"), vertex("vertex2")) {
+                    if parents[child].is_none() && parent != child {
+                        parents[child] = Some(parent);
+                    }
+                }
+            }
+        }
+        let ages = vertices
+            .iter()
+            .map(|vertex| vertex.get("age").and_then(property_value))
+            .collect::<Vec<_>>();
+
+        self.load(particles, parents, ages);
+        Ok(())
     }
 
-    /// Returns true if the particle has traveled
-    /// too far outside the current bounding sphere.
-    #[inline]
-    fn out_of_bounds(&self, point: &Point3D) -> bool {
-        point.magnitude_squared() > (self.bounding_radius * 2.0).square()
+    /// Replaces the aggregate with the particles from a CSV file as
+    /// written by [`write_csv()`](Self::write_csv).
+    ///
+    /// The header names the columns; `x`, `y` & `z` are required.
+    /// Particles reference their parent by `id`, a negative or empty
+    /// parent marks a seed. Without an `id` column parents are row
+    /// numbers, starting at zero. `scale` or `radius` and `age`
+    /// columns are used when present. A first line naming `x`, `y` &
+    /// `z` is the header; otherwise the file is read as
+    /// `id,parent,x,y,z`.
+    pub fn read_csv(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+
+        fn split(line: &str) -> Vec<&str> {
+            line.split(',').map(str::trim).collect()
+        }
+        let mut columns = vec!["id", "parent", "x", "y", "z"];
+        let mut rows = Vec::new();
+        if let Some((number, line)) = lines.next() {
+            let fields = split(line);
+            if ["x", "y", "z"].iter().all(|name| fields.contains(name)) {
+                columns = fields;
+            } else {
+                rows.push((number, fields));
+            }
+        }
+        rows.extend(lines.map(|(number, line)| (number, split(line))));
+
+        let column = |name| columns.iter().position(|&column| column == name);
+        let value = |row: &(usize, Vec<&str>), column: Option<usize>| -> Result<Option<f64>> {
+            match column.and_then(|column| row.1.get(column)) {
+                None | Some(&"") => Ok(None),
+                Some(field) => field.parse::<f64>().map(Some).map_err(|_| {
+                    format!(
+                        "Invalid number '{}' on line {} of '{}'.",
+                        field,
+                        row.0 + 1,
+                        path.display()
+                    )
+                    .into()
+                }),
+            }
+        };
+        let coordinate = |row: &(usize, Vec<&str>), name| -> Result<f32> {
+            value(row, column(name))?.map(|x| x as f32).ok_or_else(|| {
+                format!(
+                    "No '{}' on line {} of '{}'.",
+                    name,
+                    row.0 + 1,
+                    path.display()
+                )
+                .into()
+            })
+        };
+
+        let default_scale = self.default_scale();
+        let mut particles = Vec::with_capacity(rows.len());
+        let mut ids = HashMap::with_capacity(rows.len());
+        let mut ages = Vec::with_capacity(rows.len());
+        for (index, row) in rows.iter().enumerate() {
+            particles.push((
+                Point3D::new(
+                    coordinate(row, "x")?,
+                    coordinate(row, "y")?,
+                    coordinate(row, "z")?,
+                ),
+                match value(row, column("scale"))? {
+                    Some(scale) => scale as f32,
+                    None => value(row, column("radius"))?
+                        .map_or(default_scale, |radius| 2.0 * radius as f32),
+                },
+            ));
+            ids.insert(
+                value(row, column("id"))?.map_or(index as i64, |id| id as i64),
+                index,
+            );
+            ages.push(value(row, column("age"))?);
+        }
+
+        let mut parents = Vec::with_capacity(rows.len());
+        for row in &rows {
+            parents.push(
+                value(row, column("parent"))?
+                    .filter(|&parent| 0.0 <= parent)
+                    .and_then(|parent| ids.get(&(parent as i64)).cloned()),
+            );
+        }
+
+        self.load(particles, parents, ages);
+        Ok(())
     }
 
-    /// Returns true if the point should attach to the specified
-    /// parent particle. This is only called when the point is already
-    /// within the required attraction distance.
-    #[inline]
-    fn should_join(&mut self, parent: Index) -> bool {
-        self.join_attempts[parent as usize] += 1;
-        if self.join_attempts[parent as usize] < self.stubbornness {
-            false
-        } else {
-            //let mut rng = rand::thread_rng();
-            self.rng.gen_range(0.0..1.0) <= self.stickiness
+    /// Scale of loaded particles that do not have one.
+    fn default_scale(&self) -> f32 {
+        self.config
+            .particle
+            .scale
+            .as_ref()
+            .map_or(2.0, |scale| scale.evaluate(0.0, 0.0))
+    }
+
+    /// Replaces the aggregate with the given particles, parents
+    /// (indices into `particles`) and, where known, ages.
+    fn load(
+        &mut self,
+        particles: Vec<(Point3D, f32)>,
+        mut parents: Vec<Option<Index>>,
+        ages: Vec<Option<f64>>,
+    ) {
+        // Parents have to come before their children.
+        let order = tree_order(&mut parents);
+        let mut new_indices = vec![0; particles.len()];
+        order
+            .iter()
+            .enumerate()
+            .for_each(|(new_index, &index)| new_indices[index] = new_index);
+
+        self.aggregate = Aggregate::new(&self.config);
+        self.update_parameters(0.0);
+        for (new_index, &index) in order.iter().enumerate() {
+            let (point, scale) = particles[index];
+            self.aggregate.add(
+                &point,
+                scale,
+                parents[index].map(|parent| new_indices[parent]),
+            );
+            if let Some(age) = ages[index] {
+                self.aggregate.ages[new_index] = age as u32;
+            }
         }
     }
 
-    /// Computes the final placement of the particle.
-    #[inline]
-    fn place_particle(&self, point: &Point3D, parent: Index) -> Point3D {
-        lerp_points(
-            &self.particles[parent as usize].0,
-            point,
-            self.particle_spacing,
-        )
+    /// Diffuses one new particle and adds it to the model.
+    ///
+    /// The walker is abandoned after `max_steps` or when the
+    /// `deadline` passes.
+    fn diffuse_particle(
+        &mut self,
+        scale: f32,
+        max_steps: u64,
+        deadline: Option<Instant>,
+    ) -> Diffusion {
+        // compute particle starting location
+        let mut particle = self.spawn.spawn(&self.aggregate, &mut self.rng);
+        self.walk.reset();
+
+        // do the random walk
+        for step in 0..max_steps {
+            if 0 == step % STEPS_PER_TIME_CHECK
+                && deadline.is_some_and(|deadline| deadline <= Instant::now())
+            {
+                return Diffusion::OutOfTime;
+            }
+
+            // get distance to nearest other particle
+            let (parent, distance) = match self.aggregate.nearest_surface(&particle, scale) {
+                Some((parent, distance)) => (Some(parent), distance),
+                None => (None, f32::INFINITY),
+            };
+
+            // check if close enough to stick to the substrate
+            let height = self.substrate.map_or(f32::INFINITY, |substrate| {
+                particle.y - substrate - self.aggregate.contact_radius(scale)
+            });
+            if height < self.aggregate.attraction_distance && height < distance {
+                // rest the particle on the substrate
+                particle.y = self.substrate.unwrap() + self.aggregate.resting_height(scale);
+
+                self.aggregate.add(&particle, scale, None);
+                return Diffusion::Stuck;
+            }
+
+            // check if close enough to join
+            if let Some(parent) = parent {
+                if distance < self.aggregate.attraction_distance {
+                    match self
+                        .join
+                        .contact(&self.aggregate, &particle, parent, &mut self.rng)
+                    {
+                        Contact::Join => (),
+                        Contact::Repel => {
+                            // push particle away a bit
+                            let (parent_position, parent_scale) = self.aggregate.particles[parent];
+                            particle = self.aggregate.lerp_points(
+                                &parent_position,
+                                &particle,
+                                self.aggregate.contact_radius(parent_scale)
+                                    + self.aggregate.contact_radius(scale)
+                                    + self.aggregate.attraction_distance
+                                    + self.aggregate.repulsion_distance,
+                            );
+                            continue;
+                        }
+                        Contact::Absorb => {
+                            // start over with a new particle
+                            particle = self.spawn.spawn(&self.aggregate, &mut self.rng);
+                            self.walk.reset();
+                            continue;
+                        }
+                    }
+
+                    // adjust particle position in relation to its parent
+                    particle = self
+                        .placement
+                        .place(&self.aggregate, &particle, parent, scale);
+
+                    // add the point
+                    self.aggregate.add(&particle, scale, Some(parent));
+
+                    // and its symmetric copies, right away, so the next
+                    // particle already sees them
+                    if let Some(symmetry) = &self.symmetry {
+                        let parent_position = self.aggregate.particles[parent].0;
+                        for (copy, parent_copy) in symmetry
+                            .copies(&particle)
+                            .zip(symmetry.copies(&parent_position))
+                        {
+                            let copy = self.aggregate.wrap(&copy);
+                            // skip copies landing on top of the
+                            // particle or another copy, e.g. on a
+                            // mirror plane
+                            let nearest = self.aggregate.nearest_particle(&copy).unwrap();
+                            if self
+                                .aggregate
+                                .difference(&copy, &self.aggregate.particles[nearest].0)
+                                .magnitude_squared()
+                                >= (0.5 * self.aggregate.joining_distance(nearest, scale)).square()
+                            {
+                                let parent = self
+                                    .aggregate
+                                    .nearest_particle(&self.aggregate.wrap(&parent_copy))
+                                    .unwrap();
+                                self.aggregate.add(&copy, scale, Some(parent));
+                            }
+                        }
+                    }
+                    return Diffusion::Stuck;
+                }
+            }
+
+            // move randomly
+            let previous = particle;
+            particle = self.walk.step(
+                &self.aggregate,
+                &particle,
+                distance.min(height),
+                &mut self.rng,
+            );
+
+            // bend the step towards the drift direction, keeping its
+            // length so walkers still cannot tunnel through branches
+            let step = particle - previous;
+            let length = step.magnitude();
+            if 0.0 != self.drift && 0.0 < length {
+                let bent = step / length + self.drift * self.drift_direction;
+                if f32::EPSILON < bent.magnitude_squared() {
+                    particle = previous + bent.normalize() * length;
+                }
+            }
+
+            // wrap around the periodic box
+            particle = self.aggregate.wrap(&particle);
+
+            // reset to a new random particle (or bring it back) if
+            // it is too far away
+            if self.spawn.out_of_bounds(&self.aggregate, &particle) {
+                particle = self
+                    .spawn
+                    .reinject(&self.aggregate, &particle, &mut self.rng);
+                self.walk.reset();
+            }
+        }
+
+        Diffusion::Abandoned
     }
 
     fn instance_obj_nsi(&self, c: &nsi::Context, instance_obj_path: &Path) {
@@ -420,9 +2248,9 @@ impl Model {
                 );
 
                 let mut matrix =
-                    Vec::<f64>::with_capacity(self.particles.len() * 16);
+                    Vec::<f64>::with_capacity(self.aggregate.particles.len() * 16);
 
-                self.particles.iter().for_each(|p| {
+                self.aggregate.particles.iter().for_each(|p| {
                     matrix.extend_from_slice(&[
                         p.1 as f64,
                         0.0,
@@ -465,11 +2293,11 @@ impl Model {
                 );
 
                 let mut particle_positions =
-                    Vec::<f32>::with_capacity(3 * self.particles.len());
+                    Vec::<f32>::with_capacity(3 * self.aggregate.particles.len());
                 let mut particle_widths =
-                    Vec::<f32>::with_capacity(self.particles.len());
+                    Vec::<f32>::with_capacity(self.aggregate.particles.len());
 
-                self.particles.iter().for_each(|p| {
+                self.aggregate.particles.iter().for_each(|p| {
                     p.0.iter().for_each(|c| particle_positions.push(*c));
                     particle_widths.push(p.1);
                 });
@@ -484,7 +2312,17 @@ impl Model {
             }
         }
 
-        self.particles.clear();
+        // Cluster each particle belongs to, e.g. to color competing
+        // clusters.
+        c.set_attribute(
+            "particles",
+            &[nsi::integers!(
+                "cluster",
+                bm::cast_slice(self.aggregate.clusters.as_slice())
+            )],
+        );
+
+        self.aggregate.particles.clear();
 
         // Get 3Delight path to find shaders.
         let delight = {
@@ -525,7 +2363,7 @@ impl Model {
                     0.0,
                     0.0,
                     0.0,
-                    4.0f64 * self.bounding_radius as f64,
+                    4.0f64 * self.aggregate.bounding_radius as f64,
                     1.0,
                 ]
             )],
@@ -703,3 +2541,187 @@ impl Model {
         c.render_control(&[nsi::string!("action", "wait")]);
     }
 }
+
+/// Returns the value of a scalar PLY property.
+fn property_value(property: &Property) -> Option<f64> {
+    Some(match *property {
+        Property::Char(value) => value as f64,
+        Property::UChar(value) => value as f64,
+        Property::Short(value) => value as f64,
+        Property::UShort(value) => value as f64,
+        Property::Int(value) => value as f64,
+        Property::UInt(value) => value as f64,
+        Property::Float(value) => value as f64,
+        Property::Double(value) => value,
+        _ => return None,
+    })
+}
+
+/// Returns the particle indices in an order that has every parent
+/// before its children.
+///
+/// Links that are part of a cycle are removed from `parents`.
+fn tree_order(parents: &mut [Option<Index>]) -> Vec<Index> {
+    if parents
+        .iter()
+        .enumerate()
+        .all(|(index, parent)| parent.is_none_or(|parent| parent < index))
+    {
+        return (0..parents.len()).collect();
+    }
+
+    let mut children = vec![Vec::new(); parents.len()];
+    for (index, parent) in parents.iter().enumerate() {
+        if let Some(parent) = *parent {
+            children[parent].push(index);
+        }
+    }
+
+    let mut order = Vec::with_capacity(parents.len());
+    let mut visited = vec![false; parents.len()];
+    // Start at the seeds. Whatever is left over afterwards hangs off
+    // a cycle, which gets broken up.
+    let roots = (0..parents.len())
+        .filter(|&index| parents[index].is_none())
+        .chain(0..parents.len())
+        .collect::<Vec<_>>();
+    for root in roots {
+        if visited[root] {
+            continue;
+        }
+        parents[root] = None;
+        let mut stack = vec![root];
+        visited[root] = true;
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            for &child in children[index].iter().rev() {
+                if !visited[child] {
+                    visited[child] = true;
+                    stack.push(child);
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Writes a USD array attribute on a single line.
+fn write_usd_array<T>(
+    out: &mut dyn Write,
+    indent: usize,
+    declaration: &str,
+    values: impl Iterator<Item = T>,
+    format: impl Fn(T) -> String,
+) -> std::io::Result<()> {
+    write_usd_values(out, indent, declaration, values, format)?;
+    writeln!(out)
+}
+
+/// Writes a USD array attribute without ending the line, so metadata
+/// can follow it.
+fn write_usd_values<T>(
+    out: &mut dyn Write,
+    indent: usize,
+    declaration: &str,
+    values: impl Iterator<Item = T>,
+    format: impl Fn(T) -> String,
+) -> std::io::Result<()> {
+    write!(out, "{:indent$}{} = [", "", declaration, indent = indent)?;
+    for (i, value) in values.enumerate() {
+        if 0 < i {
+            write!(out, ", ")?;
+        }
+        write!(out, "{}", format(value))?;
+    }
+    write!(out, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An aggregate periodic in X & Y with a box of 10 around the
+    /// origin.
+    fn periodic_aggregate() -> Aggregate {
+        let mut config = Config::default();
+        config.aggregation.periodic_box = Some([10.0, 10.0, 0.0]);
+        Aggregate::new(&config)
+    }
+
+    #[test]
+    fn wrap_moves_points_into_the_box() {
+        let aggregate = periodic_aggregate();
+        assert_eq!(
+            aggregate.wrap(&Point3D::new(6.0, -7.0, 100.0)),
+            Point3D::new(-4.0, 3.0, 100.0)
+        );
+        assert_eq!(
+            aggregate.wrap(&Point3D::new(4.0, -5.0, 0.0)),
+            Point3D::new(4.0, -5.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn difference_takes_the_minimum_image() {
+        let aggregate = periodic_aggregate();
+        // Across the box edge in X, not in Z.
+        assert_eq!(
+            aggregate.difference(&Point3D::new(4.5, 0.0, 4.5), &Point3D::new(-4.5, 0.0, -4.5)),
+            Point3D::new(-1.0, 0.0, 9.0)
+        );
+        assert_eq!(
+            aggregate.difference(&Point3D::new(1.0, -4.0, 0.0), &Point3D::new(-1.0, 4.0, 0.0)),
+            Point3D::new(2.0, 2.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn nearest_particle_looks_across_the_box_edge() {
+        let mut aggregate = periodic_aggregate();
+        aggregate.add(&Point3D::new(4.5, 0.0, 0.0), 1.0, None);
+        aggregate.add(&Point3D::new(0.0, 0.0, 0.0), 1.0, None);
+        assert_eq!(
+            aggregate.nearest_particle(&Point3D::new(-4.5, 0.0, 0.0)),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn tree_order_puts_parents_first() {
+        // 1 is the seed of 1 → 2 → 0 → 3.
+        let mut parents = vec![Some(2), None, Some(1), Some(0)];
+        assert_eq!(tree_order(&mut parents), vec![1, 2, 0, 3]);
+        assert_eq!(parents, vec![Some(2), None, Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn tree_order_breaks_cycles() {
+        let mut parents = vec![Some(1), Some(0), None];
+        assert_eq!(tree_order(&mut parents), vec![2, 0, 1]);
+        assert_eq!(parents, vec![None, Some(0), None]);
+    }
+
+    #[test]
+    fn load_reorders_children_after_parents() {
+        let mut model = Model::new(&Config::default()).unwrap();
+        model.load(
+            vec![
+                (Point3D::new(2.0, 0.0, 0.0), 1.0),
+                (Point3D::new(0.0, 0.0, 0.0), 1.0),
+                (Point3D::new(1.0, 0.0, 0.0), 1.0),
+            ],
+            vec![Some(2), None, Some(1)],
+            vec![Some(2.0), Some(0.0), None],
+        );
+
+        let aggregate = &model.aggregate;
+        assert_eq!(aggregate.parents(), &[None, Some(0), Some(1)]);
+        let x = aggregate
+            .particles()
+            .iter()
+            .map(|(point, _)| point.x)
+            .collect::<Vec<_>>();
+        assert_eq!(x, vec![0.0, 1.0, 2.0]);
+        assert_eq!(aggregate.ages()[2], 2);
+    }
+}
@@ -0,0 +1,285 @@
+//! glTF 2.0 scenes.
+use crate::dla::Point3D;
+use serde_json::{json, Value};
+use std::{
+    f32::consts::{PI, TAU},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Target of buffer views holding vertex attributes.
+const ARRAY_BUFFER: u32 = 34962;
+/// Target of buffer views holding vertex indices.
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+/// Triangles of a mesh to put into a glTF file.
+#[derive(Clone, Debug, Default)]
+pub struct Primitive {
+    pub positions: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+impl Primitive {
+    /// A sphere of diameter one around the origin.
+    pub fn sphere(segments: u32, rings: u32) -> Self {
+        let mut sphere = Primitive::default();
+        for ring in 0..=rings {
+            let theta = PI * ring as f32 / rings as f32;
+            for segment in 0..=segments {
+                let phi = TAU * segment as f32 / segments as f32;
+                let normal = [
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    -theta.sin() * phi.sin(),
+                ];
+                sphere.normals.extend(&normal);
+                sphere.positions.extend(normal.iter().map(|x| 0.5 * x));
+            }
+        }
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let a = ring * (segments + 1) + segment;
+                let b = a + segments + 1;
+                sphere.indices.extend(&[a, b, a + 1, a + 1, b, b + 1]);
+            }
+        }
+        sphere
+    }
+}
+
+/// Assembles the JSON document & binary buffer of a glTF file.
+#[derive(Debug, Default)]
+pub struct Gltf {
+    buffer: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+    materials: Vec<Value>,
+    extensions_used: Vec<&'static str>,
+}
+
+impl Gltf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `data` to the buffer as a new buffer view and returns
+    /// its index.
+    fn add_buffer_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        // Accessors need their data aligned to four bytes.
+        self.buffer.resize((self.buffer.len() + 3) & !3, 0);
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": data.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.buffer.extend_from_slice(data);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    /// Adds an accessor for floats grouped by `kind`: `"SCALAR"`,
+    /// `"VEC2"` or `"VEC3"`. Bounds are always included as positions
+    /// need them.
+    pub fn add_floats(&mut self, values: &[f32], kind: &str, target: Option<u32>) -> usize {
+        let components = match kind {
+            "VEC3" => 3,
+            "VEC2" => 2,
+            _ => 1,
+        };
+        let mut min = vec![f32::INFINITY; components];
+        let mut max = vec![f32::NEG_INFINITY; components];
+        for element in values.chunks(components) {
+            for (i, value) in element.iter().enumerate() {
+                min[i] = min[i].min(*value);
+                max[i] = max[i].max(*value);
+            }
+        }
+
+        let bytes = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        let view = self.add_buffer_view(&bytes, target);
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len() / components,
+            "type": kind,
+            "min": min,
+            "max": max,
+        }));
+        self.accessors.len() - 1
+    }
+
+    /// Adds an accessor for triangle indices.
+    pub fn add_indices(&mut self, indices: &[u32]) -> usize {
+        let bytes = indices
+            .iter()
+            .flat_map(|index| index.to_le_bytes())
+            .collect::<Vec<_>>();
+        let view = self.add_buffer_view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+
+    /// Adds a metallic-roughness material and returns its index.
+    pub fn add_material(&mut self, color: [f32; 3], metallic: f32, roughness: f32) -> usize {
+        self.materials.push(json!({
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [color[0], color[1], color[2], 1.0],
+                "metallicFactor": metallic,
+                "roughnessFactor": roughness,
+            }
+        }));
+        self.materials.len() - 1
+    }
+
+    /// Adds a mesh made of the given triangle primitives and returns
+    /// its index.
+    pub fn add_mesh(&mut self, primitives: &[Primitive], material: usize) -> usize {
+        let primitives = primitives
+            .iter()
+            .map(|primitive| {
+                let position = self.add_floats(&primitive.positions, "VEC3", Some(ARRAY_BUFFER));
+                let mut attributes = json!({ "POSITION": position });
+                if !primitive.normals.is_empty() {
+                    attributes["NORMAL"] =
+                        json!(self.add_floats(&primitive.normals, "VEC3", Some(ARRAY_BUFFER)));
+                }
+                json!({
+                    "attributes": attributes,
+                    "indices": self.add_indices(&primitive.indices),
+                    "material": material,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.meshes.push(json!({ "primitives": primitives }));
+        self.meshes.len() - 1
+    }
+
+    /// Adds a node drawing `mesh` once at each of the `positions`,
+    /// scaled uniformly by `scales`, through `EXT_mesh_gpu_instancing`.
+    pub fn add_instances(&mut self, mesh: usize, positions: &[Point3D], scales: &[f32]) {
+        let translations = positions
+            .iter()
+            .flat_map(|point| [point.x, point.y, point.z])
+            .collect::<Vec<_>>();
+        let scales = scales
+            .iter()
+            .flat_map(|&scale| [scale; 3])
+            .collect::<Vec<_>>();
+
+        let translation = self.add_floats(&translations, "VEC3", None);
+        let scale = self.add_floats(&scales, "VEC3", None);
+        if !self.extensions_used.contains(&"EXT_mesh_gpu_instancing") {
+            self.extensions_used.push("EXT_mesh_gpu_instancing");
+        }
+        self.nodes.push(json!({
+            "mesh": mesh,
+            "extensions": {
+                "EXT_mesh_gpu_instancing": {
+                    "attributes": {
+                        "TRANSLATION": translation,
+                        "SCALE": scale,
+                    }
+                }
+            }
+        }));
+    }
+
+    /// Adds a node with a point cloud at `positions`. The `scales`
+    /// are kept as the custom `_SCALE` attribute since glTF points
+    /// have no size.
+    pub fn add_points(&mut self, positions: &[Point3D], scales: &[f32], material: usize) {
+        let points = positions
+            .iter()
+            .flat_map(|point| [point.x, point.y, point.z])
+            .collect::<Vec<_>>();
+        let position = self.add_floats(&points, "VEC3", Some(ARRAY_BUFFER));
+        let scale = self.add_floats(scales, "SCALAR", Some(ARRAY_BUFFER));
+        self.meshes.push(json!({
+            "primitives": [{
+                "attributes": {
+                    "POSITION": position,
+                    "_SCALE": scale,
+                },
+                "material": material,
+                // Points
+                "mode": 0,
+            }]
+        }));
+        self.nodes.push(json!({ "mesh": self.meshes.len() - 1 }));
+    }
+
+    fn document(&self, uri: Option<String>) -> Value {
+        let mut buffer = json!({ "byteLength": self.buffer.len() });
+        if let Some(uri) = uri {
+            buffer["uri"] = json!(uri);
+        }
+        let mut document = json!({
+            "asset": {
+                "version": "2.0",
+                "generator": "rdla",
+            },
+            "scene": 0,
+            "scenes": [{ "nodes": (0..self.nodes.len()).collect::<Vec<_>>() }],
+            "nodes": self.nodes,
+            "meshes": self.meshes,
+            "materials": self.materials,
+            "accessors": self.accessors,
+            "bufferViews": self.buffer_views,
+            "buffers": [buffer],
+        });
+        if !self.extensions_used.is_empty() {
+            document["extensionsUsed"] = json!(self.extensions_used);
+        }
+        document
+    }
+
+    /// Writes a `.gltf` JSON file with the binary data in a `.bin`
+    /// file next to it or, if the extension is `.glb`, a single binary
+    /// file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        if Some("glb") == path.extension().and_then(|extension| extension.to_str()) {
+            let mut json = serde_json::to_vec(&self.document(None))?;
+            // Chunks are padded to four bytes; JSON with spaces.
+            json.resize((json.len() + 3) & !3, b' ');
+            let mut buffer = self.buffer.clone();
+            buffer.resize((buffer.len() + 3) & !3, 0);
+
+            out.write_all(b"glTF")?;
+            out.write_all(&2u32.to_le_bytes())?;
+            out.write_all(&(12 + 8 + json.len() as u32 + 8 + buffer.len() as u32).to_le_bytes())?;
+            out.write_all(&(json.len() as u32).to_le_bytes())?;
+            out.write_all(b"JSON")?;
+            out.write_all(&json)?;
+            out.write_all(&(buffer.len() as u32).to_le_bytes())?;
+            out.write_all(b"BIN\0")?;
+            out.write_all(&buffer)?;
+        } else {
+            let bin_path = path.with_extension("bin");
+            File::create(&bin_path)?.write_all(&self.buffer)?;
+            let uri = bin_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string());
+            serde_json::to_writer_pretty(&mut out, &self.document(uri))?;
+        }
+        out.flush()
+    }
+}
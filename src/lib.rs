@@ -0,0 +1,241 @@
+//! Diffusion limited aggregation of point clouds.
+//!
+//! The [`Model`] grows an aggregate one random walk at a time. How
+//! walkers spawn, move, stick and get placed is delegated to the
+//! traits in the [`strategy`] module so variants can be tried without
+//! forking this crate.
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_derive::Deserialize;
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate if_chain;
+
+#[allow(deprecated)]
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        ParseInt(::std::num::ParseIntError);
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub post_process: PostProcess,
+    #[serde(default)]
+    pub dump: Dump,
+    pub particle: Particle,
+    pub material: Material,
+    pub environment: Environment,
+    pub nsi_render: NsiRender,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NsiRender {
+    pub resolution: Option<u32>,
+    pub shading_samples: Option<u32>,
+    pub oversampling: Option<u32>,
+    pub bucket_order: Option<String>,
+    pub output: Output,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Size {
+    pub kind: Option<String>,
+    pub deviation: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Material {
+    pub color: Option<[f32; 3]>,
+    pub roughness: Option<f32>,
+    pub specular_level: Option<f32>,
+    pub metallic: Option<f32>,
+    pub anisotropy: Option<f32>,
+    pub sss_weight: Option<f32>,
+    pub sss_color: Option<[f32; 3]>,
+    pub sss_scale: Option<f32>,
+    pub incandescence: Option<[f32; 3]>,
+    pub incandescence_intensity: Option<f32>,
+    pub incandescence_multiplier: Option<[f32; 3]>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Environment {
+    pub texture: Option<String>,
+    pub intensity: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Aggregation {
+    pub show_progress: Option<bool>,
+    pub random_seed: Option<u64>,
+    pub particles: Option<u32>,
+    pub spacing: Option<Curve>,
+    pub attraction_distance: Option<Curve>,
+    pub repulsion_distance: Option<Curve>,
+    pub stubbornness: Option<Curve>,
+    pub stickiness: Option<Curve>,
+    pub drift: Option<Curve>,
+    pub drift_direction: Option<[f32; 3]>,
+    pub periodic_box: Option<[f32; 3]>,
+    pub contact: Option<String>,
+    pub start_shape: StartShape,
+    #[serde(default)]
+    pub walk: Walk,
+    #[serde(default)]
+    pub join: Join,
+    #[serde(default)]
+    pub placement: Placement,
+    #[serde(default)]
+    pub symmetry: SymmetryGroup,
+    pub deposition: Option<Deposition>,
+    #[serde(default)]
+    pub spawn: Spawn,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PostProcess {
+    pub min_branch_length: Option<u32>,
+    pub min_strahler_order: Option<u32>,
+    pub smoothing_iterations: Option<u32>,
+    pub smoothing: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Dump {
+    pub geometry: Option<String>,
+    pub encoding: Option<String>,
+    pub edges: Option<bool>,
+    pub instancing: Option<bool>,
+    pub csv_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub surface: Surface,
+    #[serde(default)]
+    pub voxels: Voxels,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Surface {
+    pub radius: Option<String>,
+    pub pipe_exponent: Option<f32>,
+    pub blend: Option<f32>,
+    pub iso: Option<f32>,
+    pub voxel_size: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Voxels {
+    pub resolution: Option<u32>,
+    pub kernel_width: Option<f32>,
+    pub channels: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Particle {
+    pub scale: Option<Curve>,
+    #[serde(default)]
+    pub size: Size,
+    pub instance_geo: Option<String>,
+    pub subdivision: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StartShape {
+    pub shape: Option<String>,
+    pub diameter: Option<f32>,
+    pub particles: Option<u32>,
+    pub points: Option<Vec<[f32; 3]>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Walk {
+    pub kind: Option<String>,
+    pub exponent: Option<f32>,
+    pub turn_angle: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Join {
+    pub kind: Option<String>,
+    pub radius: Option<f32>,
+    pub probabilities: Option<Vec<f32>>,
+    pub threshold: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Placement {
+    pub kind: Option<String>,
+    pub axes: Option<String>,
+    pub directions: Option<Vec<[f32; 3]>>,
+    pub strength: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SymmetryGroup {
+    pub kind: Option<String>,
+    pub folds: Option<u32>,
+    pub axis: Option<[f32; 3]>,
+    pub planes: Option<Vec<[f32; 3]>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Spawn {
+    pub center: Option<String>,
+    pub radius_factor: Option<f32>,
+    pub kill_factor: Option<f32>,
+    pub escape: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    pub walker_steps: Option<u64>,
+    pub abandoned_walkers: Option<u32>,
+    pub time: Option<f32>,
+    pub radius: Option<f32>,
+    pub reach: Option<[f32; 3]>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Deposition {
+    pub substrate: Option<f32>,
+    pub width: Option<f32>,
+    pub spawn_height: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Output {
+    pub file_name: Option<String>,
+    pub cloud_render: Option<bool>,
+    pub display: Option<bool>,
+}
+
+mod dla;
+pub use dla::*;
+
+pub mod curve;
+pub use curve::*;
+
+pub mod gltf;
+pub use gltf::*;
+
+pub mod mesh;
+pub use mesh::*;
+
+pub mod post_process;
+pub use post_process::*;
+
+pub mod strategy;
+pub use strategy::*;
+
+pub mod symmetry;
+pub use symmetry::*;
+
+pub mod volume;
+pub use volume::*;
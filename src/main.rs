@@ -8,102 +8,11 @@ use mimalloc::MiMalloc;
 static GLOBAL: MiMalloc = MiMalloc;*/
 
 use clap::{load_yaml, App};
-use indicatif::{ProgressBar, ProgressStyle};
-use serde_derive::Deserialize;
+use rdla::*;
 use std::io::Write;
 
 use std::{fs::File, io::prelude::*, path::Path};
 
-#[macro_use]
-extern crate error_chain;
-
-#[macro_use]
-extern crate if_chain;
-
-#[allow(deprecated)]
-error_chain! {
-    foreign_links {
-        Io(std::io::Error);
-        ParseInt(::std::num::ParseIntError);
-    }
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-pub struct Config {
-    aggregation: Aggregation,
-    particle: Particle,
-    material: Material,
-    environment: Environment,
-    nsi_render: NsiRender,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct NsiRender {
-    resolution: Option<u32>,
-    shading_samples: Option<u32>,
-    oversampling: Option<u32>,
-    bucket_order: Option<String>,
-    output: Output,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Material {
-    color: Option<[f32; 3]>,
-    roughness: Option<f32>,
-    specular_level: Option<f32>,
-    metallic: Option<f32>,
-    anisotropy: Option<f32>,
-    sss_weight: Option<f32>,
-    sss_color: Option<[f32; 3]>,
-    sss_scale: Option<f32>,
-    incandescence: Option<[f32; 3]>,
-    incandescence_intensity: Option<f32>,
-    incandescence_multiplier: Option<[f32; 3]>,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Environment {
-    texture: Option<String>,
-    intensity: Option<f32>,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Aggregation {
-    show_progress: Option<bool>,
-    random_seed: Option<u64>,
-    particles: Option<u32>,
-    spacing: Option<[f32; 2]>,
-    attraction_distance: Option<f32>,
-    repulsion_distance: Option<f32>,
-    stubbornness: Option<u8>,
-    stickiness: Option<f32>,
-    start_shape: StartShape,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Particle {
-    scale: Option<[f32; 2]>,
-    instance_geo: Option<String>,
-    subdivision: Option<bool>,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct StartShape {
-    shape: Option<String>,
-    diameter: Option<f32>,
-    particles: Option<u32>,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Output {
-    file_name: Option<String>,
-    cloud_render: Option<bool>,
-    display: Option<bool>,
-}
-
-mod dla;
-pub use dla::*;
-
 fn main() {
     if let Err(ref e) = run() {
         let stderr = &mut ::std::io::stderr();
@@ -170,20 +79,41 @@ fn run() -> Result<()> {
                 config.nsi_render.output.file_name = Some(file_name.to_string());
             }
 
-            let mut model = dla::Model::new(&config);
-            model.run();
+            let mut model = match new_model(&config, config_file) {
+                Some(model) => model,
+                None => return Ok(()),
+            };
+            if let Some(input) = app.value_of("input") {
+                read_input(&mut model, Path::new(input))?;
+            }
+            grow(&mut model, config.aggregation.show_progress.unwrap_or(true))?;
             model.render_nsi();
         }
         ("dump", Some(dump_args)) => {
             let path = Path::new(dump_args.value_of("FILE").unwrap());
 
-            let mut model = dla::Model::new(&config);
-            model.run();
+            if let Some(geometry) = dump_args.value_of("geometry") {
+                config.dump.geometry = Some(geometry.to_string());
+            }
 
-            if "ply" == path.extension().unwrap() {
-                model.write_ply(&path);
-            } else {
-                model.write_nsi(&path);
+            let mut model = match new_model(&config, config_file) {
+                Some(model) => model,
+                None => return Ok(()),
+            };
+            if let Some(input) = app.value_of("input") {
+                read_input(&mut model, Path::new(input))?;
+            }
+            grow(&mut model, config.aggregation.show_progress.unwrap_or(true))?;
+
+            match path.extension().unwrap().to_str() {
+                Some("ply") => model.write_ply(path)?,
+                Some("obj") => model.write_obj(path)?,
+                Some("nrrd") => model.write_nrrd(path)?,
+                Some("gltf") | Some("glb") => model.write_gltf(path)?,
+                Some("usda") | Some("usd") => model.write_usda(path)?,
+                Some("csv") => model.write_csv(path)?,
+                Some("geo") => model.write_geo(path)?,
+                _ => model.write_nsi(path),
             }
         }
         ("", None) => eprintln!(
@@ -193,3 +123,68 @@ fn run() -> Result<()> {
     }
     Ok(())
 }
+
+/// Clusters listed at most after a run.
+const MAX_CLUSTERS_REPORTED: usize = 16;
+
+/// Runs & post processes the model, reporting how it went if asked
+/// to.
+fn grow(model: &mut Model, report: bool) -> Result<()> {
+    let stop_reason = model.run()?;
+
+    if report {
+        println!("Stopped: {}.", stop_reason);
+        if 0 < model.abandoned_walkers() {
+            println!("Abandoned walkers: {}", model.abandoned_walkers());
+        }
+
+        let mut cluster_sizes = model
+            .aggregate()
+            .cluster_sizes()
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        if 1 < cluster_sizes.len() {
+            // Deposits can have thousands of clusters so we only list
+            // the largest ones.
+            cluster_sizes.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+            println!(
+                "Particles per cluster (largest {} of {}):",
+                cluster_sizes.len().min(MAX_CLUSTERS_REPORTED),
+                cluster_sizes.len()
+            );
+            cluster_sizes
+                .iter()
+                .take(MAX_CLUSTERS_REPORTED)
+                .for_each(|(cluster, size)| println!("{:>6}: {}", cluster, size));
+        }
+    }
+
+    let count = model.aggregate().particles().len();
+    let pruned = model.post_process();
+    if report && 0 < pruned {
+        println!("Pruned {} of {} particles.", pruned, count);
+    }
+    Ok(())
+}
+
+/// Builds the model, reporting invalid settings the same way as an
+/// unreadable config file.
+fn new_model(config: &Config, config_file: &str) -> Option<Model> {
+    match Model::new(config) {
+        Ok(model) => Some(model),
+        Err(e) => {
+            eprintln!("Config file error in '{}': {}", config_file, e);
+            None
+        }
+    }
+}
+
+/// Loads particles to continue from; the extension determines the
+/// format.
+fn read_input(model: &mut Model, path: &Path) -> Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => model.read_csv(path),
+        _ => model.read_ply(path),
+    }
+}
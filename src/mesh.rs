@@ -0,0 +1,609 @@
+//! Polygon meshes around the growth tree.
+use crate::dla::{Aggregate, Index, Point3D, Square};
+use ply_rs::{
+    ply::{
+        Addable, DefaultElement, ElementDef, Encoding, Header, PropertyDef, PropertyType,
+        ScalarType,
+    },
+    writer::Writer,
+};
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, AABB,
+};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+/// An indexed triangle mesh with per-vertex normals & UVs.
+///
+/// UVs have their own indices so they can be split along seams while
+/// the vertices stay shared. The first UVs belong to the vertices
+/// with the same index, for formats that can not split them.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub positions: Vec<Point3D>,
+    pub normals: Vec<Point3D>,
+    pub uvs: Vec<[f32; 2]>,
+    pub triangles: Vec<[u32; 3]>,
+    /// Indices into `uvs` for the corners of each triangle.
+    pub uv_triangles: Vec<[u32; 3]>,
+}
+
+impl Mesh {
+    /// Writes the mesh as Wavefront OBJ.
+    pub fn write_obj(&self, out: &mut dyn Write) -> io::Result<()> {
+        for p in &self.positions {
+            writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        for uv in &self.uvs {
+            writeln!(out, "vt {} {}", uv[0], uv[1])?;
+        }
+        for n in &self.normals {
+            writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        for (triangle, uv_triangle) in self.triangles.iter().zip(self.uv_triangles()) {
+            write!(out, "f")?;
+            for (index, uv_index) in triangle.iter().zip(&uv_triangle) {
+                // OBJ indices start at one.
+                match (self.uvs.is_empty(), self.normals.is_empty()) {
+                    (false, false) => write!(out, " {0}/{1}/{0}", index + 1, uv_index + 1)?,
+                    (true, false) => write!(out, " {0}//{0}", index + 1)?,
+                    (false, true) => write!(out, " {}/{}", index + 1, uv_index + 1)?,
+                    (true, true) => write!(out, " {}", index + 1)?,
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// UV indices of each triangle, the vertex indices if there are
+    /// no separate ones.
+    fn uv_triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+        let uv_triangles = if self.uv_triangles.is_empty() {
+            &self.triangles
+        } else {
+            &self.uv_triangles
+        };
+        uv_triangles.iter().cloned()
+    }
+
+    /// Writes the mesh as Stanford PLY, with normals & UVs as the
+    /// `nx`, `ny`, `nz`, `s` & `t` vertex properties when present.
+    ///
+    /// PLY has a single UV per vertex, so UVs split along seams are
+    /// written unsplit.
+    pub fn write_ply(&self, mut out: &mut dyn Write, encoding: Encoding) -> io::Result<()> {
+        let mut header = Header::new();
+        header.encoding = encoding;
+        header
+            .comments
+            .push("Reaction limited diffusion".to_string());
+
+        let mut names = vec!["x", "y", "z"];
+        if !self.normals.is_empty() {
+            names.extend(&["nx", "ny", "nz"]);
+        }
+        if !self.uvs.is_empty() {
+            names.extend(&["s", "t"]);
+        }
+        let mut vertex_element = ElementDef::new("vertex".to_string());
+        vertex_element.count = self.positions.len();
+        for name in &names {
+            vertex_element.properties.add(PropertyDef::new(
+                name.to_string(),
+                PropertyType::Scalar(ScalarType::Float),
+            ));
+        }
+        header.elements.add(vertex_element);
+
+        let mut face_element = ElementDef::new("face".to_string());
+        face_element.count = self.triangles.len();
+        face_element.properties.add(PropertyDef::new(
+            "vertex_indices".to_string(),
+            PropertyType::List(ScalarType::UChar, ScalarType::Int),
+        ));
+        header.elements.add(face_element);
+
+        Writer::<DefaultElement>::new().write_header(&mut out, &header)?;
+
+        for index in 0..self.positions.len() {
+            let mut values = self.positions[index].as_slice().to_vec();
+            if let Some(normal) = self.normals.get(index) {
+                values.extend(normal.as_slice());
+            }
+            if let Some(uv) = self.uvs.get(index) {
+                values.extend(uv);
+            }
+            if Encoding::Ascii == encoding {
+                let values = values.iter().map(f32::to_string).collect::<Vec<_>>();
+                writeln!(out, "{}", values.join(" "))?;
+            } else {
+                for value in values {
+                    out.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+
+        for triangle in &self.triangles {
+            if Encoding::Ascii == encoding {
+                writeln!(out, "3 {} {} {}", triangle[0], triangle[1], triangle[2])?;
+            } else {
+                out.write_all(&[3])?;
+                for index in triangle {
+                    out.write_all(&(*index as i32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a single smooth surface around all particles from a
+/// metaball field.
+///
+/// Each particle contributes `(1 - d²/R²)³` at distance `d` up to
+/// `R`, its radius plus `blend`, scaled so a lone particle's surface
+/// sits at its radius for an `iso` level of one. Lower levels swell
+/// the surface, higher ones shrink it. The field is sampled on a
+/// sparse grid with cells of `voxel_size` around the particles.
+pub fn metaballs(aggregate: &Aggregate, blend: f32, iso: f32, voxel_size: f32) -> Mesh {
+    assert!(0.0 < blend, "Metaballs need a blend radius above zero.");
+    assert!(0.0 < iso, "Metaballs need an iso level above zero.");
+
+    let particles = aggregate.particles();
+    let kernel = |x: f32| (1.0 - x.square()).max(0.0).powi(3);
+    // Reach & weight of each particle's kernel.
+    let balls = particles
+        .iter()
+        .map(|(_, scale)| {
+            let radius = 0.5 * scale;
+            let reach = radius + blend;
+            (reach, 1.0 / kernel(radius / reach))
+        })
+        .collect::<Vec<_>>();
+    let max_reach = balls.iter().map(|ball| ball.0).fold(0.0, f32::max);
+
+    let field = |point: &Point3D| {
+        iso - aggregate
+            .neighbours(point, max_reach)
+            .into_iter()
+            .map(|index| {
+                let (reach, weight) = balls[index];
+                let distance = aggregate.difference(point, &particles[index].0).magnitude();
+                weight * kernel(distance / reach)
+            })
+            .sum::<f32>()
+    };
+
+    let (positions, triangles) = polygonize(
+        &field,
+        particles
+            .iter()
+            .zip(&balls)
+            .map(|((point, _), ball)| (*point, *point, ball.0)),
+        voxel_size,
+    );
+    let normals = positions
+        .iter()
+        .map(|point| gradient(&field, point, 0.5 * voxel_size))
+        .collect();
+
+    Mesh {
+        positions,
+        normals,
+        uvs: Vec::new(),
+        triangles,
+        uv_triangles: Vec::new(),
+    }
+}
+
+/// Returns the radius of each particle following the pipe model: a
+/// particle is as thick as the pipes of all its descendants bundled
+/// up, `radius * (descendants + 1)^(1 / exponent)`.
+///
+/// An `exponent` of two keeps the cross section area; Leonardo's
+/// rule for trees.
+pub fn pipe_model_radii(aggregate: &Aggregate, exponent: f32) -> Vec<f32> {
+    let parents = aggregate.parents();
+    let mut counts = vec![1u32; parents.len()];
+    // Children come after their parents.
+    for index in (0..parents.len()).rev() {
+        if let Some(parent) = parents[index] {
+            counts[parent] += counts[index];
+        }
+    }
+    aggregate
+        .particles()
+        .iter()
+        .zip(counts)
+        .map(|((_, scale), count)| 0.5 * scale * (count as f32).powf(1.0 / exponent))
+        .collect()
+}
+
+/// Polynomial smooth minimum; blends `a` & `b` where they are closer
+/// than `k`.
+#[inline]
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h.square() * k * 0.25
+}
+
+/// A cone with rounded ends around the link from a particle's parent
+/// to the particle.
+struct Link {
+    start: Point3D,
+    axis: Point3D,
+    start_radius: f32,
+    end_radius: f32,
+}
+
+impl Link {
+    fn new(aggregate: &Aggregate, radii: &[f32], index: Index) -> Self {
+        let end = aggregate.particles()[index].0;
+        match aggregate.parents()[index] {
+            Some(parent) => {
+                let start = aggregate.particles()[parent].0;
+                Self {
+                    start,
+                    // Links across a periodic boundary stick out.
+                    axis: aggregate.difference(&end, &start),
+                    start_radius: radii[parent],
+                    end_radius: radii[index],
+                }
+            }
+            None => Self {
+                start: end,
+                axis: Point3D::zeros(),
+                start_radius: radii[index],
+                end_radius: radii[index],
+            },
+        }
+    }
+
+    /// Returns the position along the axis (0 at the start, 1 at the
+    /// end) closest to `point`.
+    #[inline]
+    fn project(&self, point: &Point3D) -> f32 {
+        let length_squared = self.axis.magnitude_squared();
+        if 0.0 < length_squared {
+            ((point - self.start).dot(&self.axis) / length_squared).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
+    #[inline]
+    fn distance(&self, point: &Point3D) -> f32 {
+        let t = self.project(point);
+        (point - (self.start + t * self.axis)).magnitude()
+            - (self.start_radius * (1.0 - t) + self.end_radius * t)
+    }
+}
+
+/// Builds a closed mesh of tubes along all parent links, blended
+/// together where they meet and capped at the tips.
+///
+/// `radii` gives the tube radius at each particle. Junctions are
+/// rounded over a distance of `blend`. The surface is sampled on a
+/// grid with cells of `voxel_size`.
+///
+/// UVs run around the tubes in `u` and along them, from the seed, in
+/// `v`, in scene units. Along the seam where `u` wraps from 1 back to
+/// 0 the UVs are split, with `u + 1` on one side; the vertices stay
+/// shared so the mesh remains closed. Where tubes meet, UVs jump
+/// between the links they are taken from.
+pub fn tubes(aggregate: &Aggregate, radii: &[f32], blend: f32, voxel_size: f32) -> Mesh {
+    let particles = aggregate.particles();
+    let parents = aggregate.parents();
+    // Links are indexed by the particle they end at. Seeds are only a
+    // sphere.
+    let links = (0..particles.len())
+        .map(|index| Link::new(aggregate, radii, index))
+        .collect::<Vec<_>>();
+
+    // Further away from a link than this it neither shapes the
+    // surface nor blends into other links.
+    let reach = 2.0 * blend + voxel_size;
+    let corner = |point: Point3D| [point.x, point.y, point.z];
+    let link_tree = RTree::bulk_load(
+        links
+            .iter()
+            .enumerate()
+            .map(|(index, link)| {
+                let end = link.start + link.axis;
+                let margin = link.start_radius.max(link.end_radius) + reach;
+                GeomWithData::new(
+                    Rectangle::from_corners(
+                        corner(link.start.inf(&end).add_scalar(-margin)),
+                        corner(link.start.sup(&end).add_scalar(margin)),
+                    ),
+                    index,
+                )
+            })
+            .collect(),
+    );
+    let links_near = |point: &Point3D, radius: f32| {
+        link_tree
+            .locate_in_envelope_intersecting(&AABB::from_corners(
+                corner(point.add_scalar(-radius)),
+                corner(point.add_scalar(radius)),
+            ))
+            .map(|link| link.data)
+            .collect::<Vec<_>>()
+    };
+
+    // Distance along the tree from the seed.
+    let mut arc_lengths = vec![0.0; particles.len()];
+    for index in 0..particles.len() {
+        if let Some(parent) = parents[index] {
+            arc_lengths[index] = arc_lengths[parent] + links[index].axis.magnitude();
+        }
+    }
+
+    // Past their reach links stop adding detail; capping keeps the
+    // field the same on either side of the boxes in the tree.
+    let field_of = |candidates: &[Index], point: &Point3D| {
+        candidates
+            .iter()
+            .map(|&index| links[index].distance(point))
+            .fold(f32::INFINITY, |a, b| smooth_min(a, b, blend))
+            .min(reach)
+    };
+    let field = |point: &Point3D| field_of(&links_near(point, 0.0), point);
+
+    let (positions, triangles) = polygonize(
+        &field,
+        links.iter().map(|link| {
+            let end = link.start + link.axis;
+            let margin = link.start_radius.max(link.end_radius) + 0.25 * blend;
+            (link.start.inf(&end), link.start.sup(&end), margin)
+        }),
+        voxel_size,
+    );
+
+    let (normals, mut uvs): (Vec<_>, Vec<_>) = positions
+        .iter()
+        .map(|point| {
+            // Shared by all samples of the gradient.
+            let candidates = links_near(point, voxel_size);
+            let normal = gradient(
+                &|point: &Point3D| field_of(&candidates, point),
+                point,
+                0.5 * voxel_size,
+            );
+
+            let nearest = candidates
+                .into_iter()
+                .min_by(|&a, &b| {
+                    links[a]
+                        .distance(point)
+                        .partial_cmp(&links[b].distance(point))
+                        .unwrap()
+                })
+                .unwrap();
+            let link = &links[nearest];
+            let t = link.project(point);
+            let direction = if 0.0 < link.axis.magnitude_squared() {
+                link.axis.normalize()
+            } else {
+                Point3D::z()
+            };
+            let helper = if direction.x.abs() < 0.9 {
+                Point3D::x()
+            } else {
+                Point3D::y()
+            };
+            let u = direction.cross(&helper).normalize();
+            let v = direction.cross(&u);
+            let offset = point - link.start;
+            let uv = [
+                offset.dot(&v).atan2(offset.dot(&u)) / std::f32::consts::TAU + 0.5,
+                arc_lengths[nearest] - (1.0 - t) * link.axis.magnitude(),
+            ];
+            (normal, uv)
+        })
+        .unzip();
+
+    // Corners of triangles spanning the seam below it get a copy of
+    // their UV past 1 so the texture does not run backwards across
+    // them. The vertices themselves stay shared.
+    let mut uv_triangles = triangles.clone();
+    let mut seam_copies = HashMap::new();
+    for uv_triangle in &mut uv_triangles {
+        let u = uv_triangle.map(|corner| uvs[corner as usize][0]);
+        if u.iter().cloned().fold(f32::NEG_INFINITY, f32::max)
+            - u.iter().cloned().fold(f32::INFINITY, f32::min)
+            <= 0.5
+        {
+            continue;
+        }
+        for corner in uv_triangle.iter_mut() {
+            let index = *corner as usize;
+            if uvs[index][0] < 0.5 {
+                *corner = *seam_copies.entry(index).or_insert_with(|| {
+                    uvs.push([uvs[index][0] + 1.0, uvs[index][1]]);
+                    (uvs.len() - 1) as u32
+                });
+            }
+        }
+    }
+
+    Mesh {
+        positions,
+        normals,
+        uvs,
+        triangles,
+        uv_triangles,
+    }
+}
+
+/// Normalized gradient of `field` at `point`, by central differences.
+pub(crate) fn gradient(field: &dyn Fn(&Point3D) -> f32, point: &Point3D, h: f32) -> Point3D {
+    let gradient = Point3D::from_fn(|i, _| {
+        let mut offset = Point3D::zeros();
+        offset[i] = h;
+        field(&(point + offset)) - field(&(point - offset))
+    });
+    if 0.0 < gradient.magnitude_squared() {
+        gradient.normalize()
+    } else {
+        Point3D::z()
+    }
+}
+
+/// Corners of a cube; bit 0, 1 & 2 step along X, Y & Z.
+const CUBE_CORNERS: [[i32; 3]; 8] = [
+    [0, 0, 0],
+    [1, 0, 0],
+    [0, 1, 0],
+    [1, 1, 0],
+    [0, 0, 1],
+    [1, 0, 1],
+    [0, 1, 1],
+    [1, 1, 1],
+];
+
+/// The six tetrahedra around the diagonal from corner 0 to 7 that fill
+/// a cube. Neighbouring cubes split their shared faces the same way
+/// so the surface has no cracks.
+const CUBE_TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 1, 3, 7],
+    [0, 1, 5, 7],
+    [0, 2, 3, 7],
+    [0, 2, 6, 7],
+    [0, 4, 5, 7],
+    [0, 4, 6, 7],
+];
+
+/// Extracts the surface where `field` is zero, with the inside being
+/// negative, as a closed triangle mesh.
+///
+/// This marches cubes split into tetrahedra, which unlike the classic
+/// marching cubes cases has no ambiguous faces that can leave holes.
+///
+/// Only grid cells overlapping one of the `regions` – boxes given by
+/// their minimum & maximum corner and a margin around them – are
+/// sampled. The field must be positive outside of them.
+pub(crate) fn polygonize(
+    field: &dyn Fn(&Point3D) -> f32,
+    regions: impl Iterator<Item = (Point3D, Point3D, f32)>,
+    voxel_size: f32,
+) -> (Vec<Point3D>, Vec<[u32; 3]>) {
+    let grid_point =
+        |cell: [i32; 3]| Point3D::new(cell[0] as f32, cell[1] as f32, cell[2] as f32) * voxel_size;
+
+    // Cells to visit, sorted so the output does not depend on hashing.
+    let mut cells = Vec::new();
+    for (min, max, margin) in regions {
+        let first = (min.add_scalar(-margin - voxel_size) / voxel_size).map(|x| x.floor() as i32);
+        let last = (max.add_scalar(margin + voxel_size) / voxel_size).map(|x| x.ceil() as i32);
+        for x in first.x..last.x {
+            for y in first.y..last.y {
+                for z in first.z..last.z {
+                    cells.push([x, y, z]);
+                }
+            }
+        }
+    }
+    cells.sort_unstable();
+    cells.dedup();
+
+    let mut values = HashMap::<[i32; 3], f32>::new();
+    let mut edge_vertices = HashMap::<([i32; 3], [i32; 3]), u32>::new();
+    let mut positions = Vec::new();
+    let mut triangles = Vec::new();
+
+    for cell in cells {
+        let corners = CUBE_CORNERS.map(|offset| {
+            [
+                cell[0] + offset[0],
+                cell[1] + offset[1],
+                cell[2] + offset[2],
+            ]
+        });
+        let corner_values = corners.map(|corner| {
+            *values.entry(corner).or_insert_with(|| {
+                let value = field(&grid_point(corner));
+                // Keep the surface off the grid points so no
+                // triangle degenerates.
+                if 0.0 == value {
+                    f32::EPSILON
+                } else {
+                    value
+                }
+            })
+        });
+
+        for tetrahedron in &CUBE_TETRAHEDRA {
+            let (inside, outside): (Vec<usize>, Vec<usize>) = tetrahedron
+                .iter()
+                .partition(|&&corner| corner_values[corner] < 0.0);
+            if inside.is_empty() || outside.is_empty() {
+                continue;
+            }
+
+            let mut vertex = |a: usize, b: usize| {
+                let key = if corners[a] < corners[b] {
+                    (corners[a], corners[b])
+                } else {
+                    (corners[b], corners[a])
+                };
+                let index = *edge_vertices.entry(key).or_insert_with(|| {
+                    let t = corner_values[a] / (corner_values[a] - corner_values[b]);
+                    positions.push(grid_point(corners[a]).lerp(&grid_point(corners[b]), t));
+                    (positions.len() - 1) as u32
+                });
+                (
+                    index,
+                    0.5 * (grid_point(corners[a]) + grid_point(corners[b])),
+                )
+            };
+
+            let polygon = match (inside.len(), outside.len()) {
+                (1, 3) => outside
+                    .iter()
+                    .map(|&o| vertex(inside[0], o))
+                    .collect::<Vec<_>>(),
+                (3, 1) => inside.iter().map(|&i| vertex(i, outside[0])).collect(),
+                // Quad, going around the edges between the two pairs.
+                _ => vec![
+                    vertex(inside[0], outside[0]),
+                    vertex(inside[0], outside[1]),
+                    vertex(inside[1], outside[1]),
+                    vertex(inside[1], outside[0]),
+                ],
+            };
+
+            // Face outwards, from the inside to the outside corners.
+            let centroid = |corners_: &[usize]| {
+                corners_
+                    .iter()
+                    .map(|&corner| grid_point(corners[corner]))
+                    .sum::<Point3D>()
+                    / corners_.len() as f32
+            };
+            let outwards = centroid(&outside) - centroid(&inside);
+
+            // Orientation is decided on the edge midpoints as
+            // interpolated positions can make triangles degenerate.
+            for i in 1..polygon.len() - 1 {
+                let (mut triangle, [a, b, c]) = (
+                    [polygon[0].0, polygon[i].0, polygon[i + 1].0],
+                    [polygon[0].1, polygon[i].1, polygon[i + 1].1],
+                );
+                if (b - a).cross(&(c - a)).dot(&outwards) < 0.0 {
+                    triangle.swap(1, 2);
+                }
+                triangles.push(triangle);
+            }
+        }
+    }
+
+    (positions, triangles)
+}
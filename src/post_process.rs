@@ -0,0 +1,238 @@
+//! Clean-up steps on the growth tree of a finished aggregate.
+//!
+//! The tree is given by the parent of each particle as returned by
+//! [`Aggregate::parents()`](crate::Aggregate::parents). Parents always
+//! come before their children.
+use crate::dla::{Aggregate, Index, Point3D};
+
+/// Returns the number of children of each particle.
+pub fn children_counts(parents: &[Option<Index>]) -> Vec<u32> {
+    let mut counts = vec![0; parents.len()];
+    parents
+        .iter()
+        .flatten()
+        .for_each(|&parent| counts[parent] += 1);
+    counts
+}
+
+/// Returns the number of links between each particle and its seed.
+pub fn depths(parents: &[Option<Index>]) -> Vec<u32> {
+    let mut depths: Vec<u32> = Vec::with_capacity(parents.len());
+    for parent in parents {
+        let depth = parent.map_or(0, |parent| depths[parent] + 1);
+        depths.push(depth);
+    }
+    depths
+}
+
+/// Returns the growth tree as polylines, each running along an
+/// unbranched chain of particles.
+///
+/// Polylines start at a seed or fork and end at a tip or fork, so
+/// every link is part of exactly one of them.
+pub fn polylines(parents: &[Option<Index>]) -> Vec<Vec<Index>> {
+    let mut children = vec![Vec::new(); parents.len()];
+    for (index, parent) in parents.iter().enumerate() {
+        if let Some(parent) = *parent {
+            children[parent].push(index);
+        }
+    }
+    // Particles in the middle of a chain.
+    let inner = |index: Index| parents[index].is_some() && 1 == children[index].len();
+
+    let mut polylines = Vec::new();
+    for start in (0..parents.len()).filter(|&index| !inner(index)) {
+        for &child in &children[start] {
+            let mut polyline = vec![start, child];
+            let mut current = child;
+            while inner(current) {
+                current = children[current][0];
+                polyline.push(current);
+            }
+            polylines.push(polyline);
+        }
+    }
+    polylines
+}
+
+/// Returns the Strahler order of each particle.
+///
+/// Tips have order one. Where two or more branches of the highest
+/// order among the children meet the order goes up by one, otherwise
+/// it is carried over.
+pub fn strahler_orders(parents: &[Option<Index>]) -> Vec<u32> {
+    // Highest order among the children & how many children have it.
+    let mut highest = vec![(0u32, 0u32); parents.len()];
+    let mut orders = vec![0; parents.len()];
+
+    // Children come after their parents so going backwards visits
+    // all children of a particle before it.
+    for index in (0..parents.len()).rev() {
+        let (order, count) = highest[index];
+        orders[index] = match count {
+            0 => 1,
+            1 => order,
+            _ => order + 1,
+        };
+        if let Some(parent) = parents[index] {
+            let parent = &mut highest[parent];
+            if parent.0 < orders[index] {
+                *parent = (orders[index], 1);
+            } else if parent.0 == orders[index] {
+                parent.1 += 1;
+            }
+        }
+    }
+    orders
+}
+
+/// Returns which particles to keep when removing all terminal
+/// branches shorter than `min_length` particles.
+///
+/// A terminal branch runs from a tip down to, but not including, the
+/// first particle with more than one child. A cluster that does not
+/// fork at all counts as a single terminal branch.
+pub fn prune_short_branches(parents: &[Option<Index>], min_length: usize) -> Vec<bool> {
+    let children = children_counts(parents);
+    let mut keep = vec![true; parents.len()];
+
+    for tip in (0..parents.len()).filter(|&index| 0 == children[index]) {
+        let mut branch = vec![tip];
+        let mut current = tip;
+        while let Some(parent) = parents[current] {
+            if 1 < children[parent] {
+                break;
+            }
+            branch.push(parent);
+            current = parent;
+        }
+
+        if branch.len() < min_length {
+            branch.iter().for_each(|&index| keep[index] = false);
+        }
+    }
+    keep
+}
+
+/// Returns which particles to keep when removing everything below
+/// the given Strahler order.
+pub fn prune_by_order(parents: &[Option<Index>], min_order: u32) -> Vec<bool> {
+    strahler_orders(parents)
+        .into_iter()
+        .map(|order| min_order <= order)
+        .collect()
+}
+
+/// Returns the positions after one pass of Laplacian smoothing along
+/// the branches.
+///
+/// Every particle with a parent and exactly one child moves
+/// `strength` of the way towards the midpoint of the two. Tips, forks
+/// and seeds stay in place. Moves bringing a particle closer to
+/// another one than they may touch are skipped.
+pub fn smooth_branches(aggregate: &Aggregate, strength: f32) -> Vec<Point3D> {
+    let particles = aggregate.particles();
+    let parents = aggregate.parents();
+
+    // The only child of each particle that has exactly one.
+    let children = children_counts(parents);
+    let mut only_children = vec![None; parents.len()];
+    for (index, parent) in parents.iter().enumerate() {
+        if let Some(parent) = *parent {
+            if 1 == children[parent] {
+                only_children[parent] = Some(index);
+            }
+        }
+    }
+
+    particles
+        .iter()
+        .enumerate()
+        .map(|(index, &(point, scale))| {
+            let (parent, child) = match (parents[index], only_children[index]) {
+                (Some(parent), Some(child)) => (parent, child),
+                _ => return point,
+            };
+
+            let midpoint = 0.5
+                * (aggregate.difference(&particles[parent].0, &point)
+                    + aggregate.difference(&particles[child].0, &point));
+            let moved = aggregate.wrap(&(point + strength * midpoint));
+
+            let collides = aggregate
+                .neighbours(
+                    &moved,
+                    aggregate.joining_distance(index, scale)
+                        + aggregate.contact_radius(aggregate.max_scale()),
+                )
+                .into_iter()
+                .filter(|&other| other != index && other != parent && other != child)
+                .any(|other| {
+                    let other_point = &particles[other].0;
+                    let distance = aggregate.difference(&moved, other_point).magnitude();
+                    distance < aggregate.difference(&point, other_point).magnitude()
+                        && distance < aggregate.joining_distance(other, scale)
+                });
+
+            if collides {
+                point
+            } else {
+                moved
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stem of three particles forking into a branch of two and a
+    /// branch of one:
+    ///
+    /// ```text
+    /// 0 - 1 - 2 - 3 - 4
+    ///          \
+    ///           5
+    /// ```
+    const Y: [Option<Index>; 6] = [None, Some(0), Some(1), Some(2), Some(3), Some(2)];
+
+    #[test]
+    fn counts_and_depths() {
+        assert_eq!(children_counts(&Y), vec![1, 1, 2, 1, 0, 0]);
+        assert_eq!(depths(&Y), vec![0, 1, 2, 3, 4, 3]);
+    }
+
+    #[test]
+    fn strahler_orders_rise_at_forks() {
+        assert_eq!(strahler_orders(&Y), vec![2, 2, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn prune_by_order_drops_low_orders() {
+        assert_eq!(
+            prune_by_order(&Y, 2),
+            vec![true, true, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn prune_short_branches_keeps_the_fork() {
+        assert_eq!(
+            prune_short_branches(&Y, 2),
+            vec![true, true, true, true, true, false]
+        );
+        assert_eq!(
+            prune_short_branches(&Y, 3),
+            vec![true, true, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn polylines_split_at_forks() {
+        assert_eq!(
+            polylines(&Y),
+            vec![vec![0, 1, 2], vec![2, 3, 4], vec![2, 5]]
+        );
+    }
+}
@@ -0,0 +1,599 @@
+//! Extension points of the random walk.
+//!
+//! [`Model`] grows the aggregate by delegating every decision about a
+//! walker to one of the traits below. The types in this module
+//! implement the classic off-lattice algorithm and are what a model
+//! uses unless told otherwise via `Model::set_*()`.
+use crate::dla::{random_point_on_unit_sphere, Aggregate, Index, Point3D, Square};
+use rand::{distributions::Distribution, Rng, RngCore};
+use rand_distr::Pareto;
+
+/// Decides where walkers start & when they are lost.
+pub trait SpawnStrategy {
+    /// Returns a random point to start a new particle.
+    fn spawn(&mut self, aggregate: &Aggregate, rng: &mut dyn RngCore) -> Point3D;
+
+    /// Returns true if the particle has traveled too far away
+    /// and should be respawned.
+    fn out_of_bounds(&self, aggregate: &Aggregate, point: &Point3D) -> bool;
+
+    /// Returns where a particle that went out of bounds at `point`
+    /// continues. By default this starts a new particle.
+    fn reinject(
+        &mut self,
+        aggregate: &Aggregate,
+        _point: &Point3D,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        self.spawn(aggregate, rng)
+    }
+}
+
+/// What a spawn strategy does with a walker that went out of bounds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Escape {
+    /// Discard it and spawn a new one at random.
+    #[default]
+    Respawn,
+    /// Move it straight back onto the spawn surface.
+    ///
+    /// This saves the walks of particles that would otherwise be
+    /// lost but biases where they come back in.
+    Reinject,
+}
+
+/// Moves a walker.
+pub trait WalkStrategy {
+    /// Called whenever a new walker starts, so state carried from
+    /// one step to the next can be dropped.
+    fn reset(&mut self) {}
+
+    /// Returns the position of the particle after one step.
+    ///
+    /// `distance` is the distance to the nearest particle in the
+    /// aggregate.
+    fn step(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        distance: f32,
+        rng: &mut dyn RngCore,
+    ) -> Point3D;
+}
+
+/// What happens to a walker touching the aggregate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Contact {
+    /// The walker sticks to its parent.
+    Join,
+    /// The walker is pushed away and keeps walking.
+    Repel,
+    /// The walker is discarded and a new one starts.
+    Absorb,
+}
+
+/// Decides whether a walker sticks to the aggregate.
+pub trait JoinRule {
+    /// Returns what happens to the point touching the specified
+    /// parent particle. This is only called when the point is
+    /// already within the required attraction distance.
+    fn contact(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        parent: Index,
+        rng: &mut dyn RngCore,
+    ) -> Contact;
+}
+
+/// Positions a sticking walker relative to its parent.
+pub trait PlacementRule {
+    /// Computes the final placement of a particle of the given
+    /// `scale`.
+    fn place(&self, aggregate: &Aggregate, point: &Point3D, parent: Index, scale: f32) -> Point3D;
+}
+
+/// Spawns walkers on the bounding sphere of the aggregate, scaled by
+/// `spawn_factor`, and lets them escape once they wander off further
+/// than `kill_factor` times its radius.
+///
+/// The sphere is centered at the origin or, with
+/// `centered_on_bounds`, at the center of the aggregate's bounding
+/// box. The latter avoids wasted walks when the seeds are not
+/// centered at the origin.
+///
+/// The default spawns on the bounding sphere around the origin and
+/// respawns walkers that got twice as far.
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingSphereSpawn {
+    pub centered_on_bounds: bool,
+    pub spawn_factor: f32,
+    pub kill_factor: f32,
+    pub escape: Escape,
+}
+
+impl Default for BoundingSphereSpawn {
+    fn default() -> Self {
+        Self {
+            centered_on_bounds: false,
+            spawn_factor: 1.0,
+            kill_factor: 2.0,
+            escape: Escape::Respawn,
+        }
+    }
+}
+
+impl BoundingSphereSpawn {
+    #[inline]
+    fn sphere(&self, aggregate: &Aggregate) -> (Point3D, f32) {
+        if self.centered_on_bounds {
+            aggregate.bounding_sphere()
+        } else {
+            (Point3D::zeros(), aggregate.bounding_radius())
+        }
+    }
+}
+
+impl SpawnStrategy for BoundingSphereSpawn {
+    #[inline]
+    fn spawn(&mut self, aggregate: &Aggregate, rng: &mut dyn RngCore) -> Point3D {
+        let (center, radius) = self.sphere(aggregate);
+        center + random_point_on_unit_sphere(rng) * radius * self.spawn_factor
+    }
+
+    #[inline]
+    fn out_of_bounds(&self, aggregate: &Aggregate, point: &Point3D) -> bool {
+        let (center, radius) = self.sphere(aggregate);
+        (point - center).magnitude_squared() > (radius * self.kill_factor).square()
+    }
+
+    fn reinject(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        match self.escape {
+            Escape::Respawn => self.spawn(aggregate, rng),
+            Escape::Reinject => {
+                let (center, radius) = self.sphere(aggregate);
+                center + (point - center).normalize() * radius * self.spawn_factor
+            }
+        }
+    }
+}
+
+/// Spawns walkers at random on a horizontal square `height` above
+/// the highest particle (or the substrate) and lets them escape once
+/// they rise `kill_factor` times as high.
+///
+/// This is for deposition onto a substrate at height `substrate`.
+/// The square is `width` wide and centered on the Y axis.
+#[derive(Clone, Copy, Debug)]
+pub struct PlaneSpawn {
+    substrate: f32,
+    width: f32,
+    height: f32,
+    kill_factor: f32,
+    escape: Escape,
+}
+
+impl PlaneSpawn {
+    pub fn new(substrate: f32, width: f32, height: f32) -> Self {
+        Self {
+            substrate,
+            width,
+            height,
+            kill_factor: 2.0,
+            escape: Escape::Respawn,
+        }
+    }
+
+    /// Sets how far, in multiples of `height`, walkers may rise
+    /// before they escape and what happens to them then.
+    pub fn with_escape(mut self, kill_factor: f32, escape: Escape) -> Self {
+        self.kill_factor = kill_factor;
+        self.escape = escape;
+        self
+    }
+
+    #[inline]
+    fn top(&self, aggregate: &Aggregate) -> f32 {
+        aggregate
+            .bounding_box()
+            .map_or(self.substrate, |(_, max)| max.y.max(self.substrate))
+    }
+}
+
+impl SpawnStrategy for PlaneSpawn {
+    fn spawn(&mut self, aggregate: &Aggregate, rng: &mut dyn RngCore) -> Point3D {
+        let half_width = 0.5 * self.width;
+        Point3D::new(
+            rng.gen_range(-half_width..half_width),
+            self.top(aggregate) + self.height,
+            rng.gen_range(-half_width..half_width),
+        )
+    }
+
+    #[inline]
+    fn out_of_bounds(&self, aggregate: &Aggregate, point: &Point3D) -> bool {
+        point.y > self.top(aggregate) + self.kill_factor * self.height
+    }
+
+    fn reinject(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        match self.escape {
+            Escape::Respawn => self.spawn(aggregate, rng),
+            Escape::Reinject => Point3D::new(point.x, self.top(aggregate) + self.height, point.z),
+        }
+    }
+}
+
+/// Takes isotropic steps as large as possible without skipping over
+/// the nearest particle but at least `repulsion_distance` long.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IsotropicWalk;
+
+impl WalkStrategy for IsotropicWalk {
+    #[inline]
+    fn step(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        distance: f32,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        let move_magnitude = aggregate
+            .repulsion_distance()
+            .max(distance - aggregate.attraction_distance());
+        point + move_magnitude * random_point_on_unit_sphere(rng)
+    }
+}
+
+/// Takes the steps of [`IsotropicWalk`] but turns each one by at most
+/// `max_turn_angle` away from the previous direction.
+///
+/// Small angles give straighter branches.
+#[derive(Clone, Copy, Debug)]
+pub struct PersistentWalk {
+    max_turn_angle: f32,
+    direction: Option<Point3D>,
+}
+
+impl PersistentWalk {
+    /// The angle is in radians; `π` is the same as an isotropic walk.
+    pub fn new(max_turn_angle: f32) -> Self {
+        Self {
+            max_turn_angle,
+            direction: None,
+        }
+    }
+}
+
+impl WalkStrategy for PersistentWalk {
+    fn reset(&mut self) {
+        self.direction = None;
+    }
+
+    fn step(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        distance: f32,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        let direction = match self.direction {
+            None => random_point_on_unit_sphere(rng),
+            Some(previous) => {
+                // Uniformly distributed inside the spherical cap
+                // around the previous direction.
+                let cos_theta = rng.gen_range(self.max_turn_angle.cos()..=1.0);
+                let sin_theta = (1.0 - cos_theta.square()).max(0.0).sqrt();
+                let phi = rng.gen_range(0.0..std::f32::consts::TAU);
+
+                let helper = if previous.x.abs() < 0.9 {
+                    Point3D::x()
+                } else {
+                    Point3D::y()
+                };
+                let u = previous.cross(&helper).normalize();
+                let v = previous.cross(&u);
+
+                (previous * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta).normalize()
+            }
+        };
+        self.direction = Some(direction);
+
+        let move_magnitude = aggregate
+            .repulsion_distance()
+            .max(distance - aggregate.attraction_distance());
+        point + move_magnitude * direction
+    }
+}
+
+/// Flies in straight lines whose lengths follow a Pareto distribution
+/// with the given `exponent`.
+///
+/// The shortest flight is the step [`IsotropicWalk`] would take.
+/// Flights longer than what is safe without touching the aggregate
+/// are continued over the following steps so walkers never tunnel
+/// through branches. Exponents below two give the heavy tail of a
+/// Lévy flight; the smaller, the more open the aggregate gets.
+#[derive(Clone, Copy, Debug)]
+pub struct LevyFlight {
+    exponent: f32,
+    direction: Point3D,
+    remaining: f32,
+}
+
+impl LevyFlight {
+    pub fn new(exponent: f32) -> Self {
+        Self {
+            exponent,
+            direction: Point3D::zeros(),
+            remaining: 0.0,
+        }
+    }
+}
+
+impl WalkStrategy for LevyFlight {
+    fn reset(&mut self) {
+        self.remaining = 0.0;
+    }
+
+    fn step(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        distance: f32,
+        rng: &mut dyn RngCore,
+    ) -> Point3D {
+        let safe_distance = aggregate
+            .repulsion_distance()
+            .max(distance - aggregate.attraction_distance())
+            .max(f32::EPSILON);
+
+        if self.remaining <= 0.0 {
+            self.direction = random_point_on_unit_sphere(rng);
+            self.remaining = Pareto::new(safe_distance, self.exponent)
+                .expect("Lévy flight exponent must be positive.")
+                .sample(rng);
+        }
+
+        let move_magnitude = self.remaining.min(safe_distance);
+        self.remaining -= move_magnitude;
+        point + move_magnitude * self.direction
+    }
+}
+
+/// Makes each particle refuse `stubbornness` walkers before sticking
+/// with a probability of `stickiness`.
+#[derive(Clone, Debug, Default)]
+pub struct StubbornJoin {
+    join_attempts: Vec<u8>,
+}
+
+impl JoinRule for StubbornJoin {
+    #[inline]
+    fn contact(
+        &mut self,
+        aggregate: &Aggregate,
+        _point: &Point3D,
+        parent: Index,
+        rng: &mut dyn RngCore,
+    ) -> Contact {
+        if self.join_attempts.len() <= parent {
+            self.join_attempts.resize(aggregate.particles().len(), 0);
+        }
+        self.join_attempts[parent] = self.join_attempts[parent].saturating_add(1);
+        if self.join_attempts[parent] < aggregate.stubbornness()
+            || rng.gen_range(0.0..1.0) > aggregate.stickiness()
+        {
+            Contact::Repel
+        } else {
+            Contact::Join
+        }
+    }
+}
+
+/// Makes the sticking probability depend on how many particles lie
+/// within `radius` of the contact point.
+///
+/// `probabilities[n - 1]` is used when there are `n` neighbours; the
+/// last entry covers everything above. Low values for one neighbour
+/// suppress growth at tips, high values for many neighbours fill in
+/// the sides and thicken branches.
+#[derive(Clone, Debug)]
+pub struct NeighbourCountJoin {
+    radius: f32,
+    probabilities: Vec<f32>,
+}
+
+impl NeighbourCountJoin {
+    pub fn new(radius: f32, probabilities: Vec<f32>) -> Self {
+        assert!(
+            !probabilities.is_empty(),
+            "At least one sticking probability is needed."
+        );
+        Self {
+            radius,
+            probabilities,
+        }
+    }
+}
+
+impl JoinRule for NeighbourCountJoin {
+    fn contact(
+        &mut self,
+        aggregate: &Aggregate,
+        point: &Point3D,
+        _parent: Index,
+        rng: &mut dyn RngCore,
+    ) -> Contact {
+        let neighbours = aggregate.neighbour_count(point, self.radius);
+        let probability = self.probabilities[neighbours
+            .saturating_sub(1)
+            .min(self.probabilities.len() - 1)];
+
+        if rng.gen_range(0.0..1.0) <= probability {
+            Contact::Join
+        } else {
+            Contact::Repel
+        }
+    }
+}
+
+/// Meakin-style noise reduction.
+///
+/// Every walker touching a particle only scores a hit on it and is
+/// discarded. Once a particle has collected `threshold` hits the next
+/// walker sticks to it and its counter starts over. This averages out
+/// the randomness of single walkers and gives smooth, needle-like
+/// branches.
+#[derive(Clone, Debug)]
+pub struct NoiseReductionJoin {
+    threshold: u32,
+    hits: Vec<u32>,
+}
+
+impl NoiseReductionJoin {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            hits: Vec::new(),
+        }
+    }
+}
+
+impl JoinRule for NoiseReductionJoin {
+    fn contact(
+        &mut self,
+        aggregate: &Aggregate,
+        _point: &Point3D,
+        parent: Index,
+        _rng: &mut dyn RngCore,
+    ) -> Contact {
+        if self.hits.len() <= parent {
+            self.hits.resize(aggregate.particles().len(), 0);
+        }
+        self.hits[parent] = self.hits[parent].saturating_add(1);
+        if self.hits[parent] < self.threshold {
+            Contact::Absorb
+        } else {
+            self.hits[parent] = 0;
+            Contact::Join
+        }
+    }
+}
+
+/// Places the particle at the [joining
+/// distance](Aggregate::joining_distance) from its parent, in the
+/// direction it came from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpacingPlacement;
+
+impl PlacementRule for SpacingPlacement {
+    #[inline]
+    fn place(&self, aggregate: &Aggregate, point: &Point3D, parent: Index, scale: f32) -> Point3D {
+        aggregate.lerp_points(
+            &aggregate.particles()[parent].0,
+            point,
+            aggregate.joining_distance(parent, scale),
+        )
+    }
+}
+
+/// Places the particle at the [joining
+/// distance](Aggregate::joining_distance) from its parent, in the
+/// direction it came from bent towards the closest of a set of
+/// preferred axes.
+///
+/// With a `strength` of zero this is the same as
+/// [`SpacingPlacement`], with one every particle snaps onto an axis.
+/// Spots already taken by another particle are skipped for the next
+/// closest axis. Six axes in a plane grow snowflakes, the cubic axes
+/// give dendrites.
+#[derive(Clone, Debug)]
+pub struct AnisotropicPlacement {
+    axes: Vec<Point3D>,
+    strength: f32,
+}
+
+impl AnisotropicPlacement {
+    /// The `axes` do not need to be normalized.
+    pub fn new(axes: Vec<Point3D>, strength: f32) -> Self {
+        assert!(!axes.is_empty(), "At least one preferred axis is needed.");
+        Self {
+            axes: axes.iter().map(|axis| axis.normalize()).collect(),
+            strength: strength.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Six axes, 60° apart, in the XY plane.
+    pub fn hexagonal(strength: f32) -> Self {
+        Self::new(
+            (0..6)
+                .map(|i| {
+                    let angle = i as f32 * std::f32::consts::FRAC_PI_3;
+                    Point3D::new(angle.cos(), angle.sin(), 0.0)
+                })
+                .collect(),
+            strength,
+        )
+    }
+
+    /// The six positive & negative coordinate axes.
+    pub fn cubic(strength: f32) -> Self {
+        Self::new(
+            vec![
+                Point3D::x(),
+                -Point3D::x(),
+                Point3D::y(),
+                -Point3D::y(),
+                Point3D::z(),
+                -Point3D::z(),
+            ],
+            strength,
+        )
+    }
+}
+
+impl PlacementRule for AnisotropicPlacement {
+    fn place(&self, aggregate: &Aggregate, point: &Point3D, parent: Index, scale: f32) -> Point3D {
+        let distance = aggregate.joining_distance(parent, scale);
+        let parent = &aggregate.particles()[parent].0;
+        let direction = aggregate.difference(point, parent).normalize();
+
+        let mut axes = self.axes.iter().collect::<Vec<_>>();
+        axes.sort_by(|a, b| b.dot(&direction).partial_cmp(&a.dot(&direction)).unwrap());
+
+        // Walkers coming from similar directions get bent onto the
+        // same spot. If it is taken the next closest axis is tried
+        // and, failing all, the direction the walker came from.
+        let free = |position: &Point3D| {
+            aggregate.nearest_particle(position).is_none_or(|nearest| {
+                aggregate
+                    .difference(position, &aggregate.particles()[nearest].0)
+                    .magnitude()
+                    >= 0.5 * aggregate.joining_distance(nearest, scale)
+            })
+        };
+        axes.into_iter()
+            .map(|axis| {
+                let bent = direction.lerp(axis, self.strength);
+                // Bending exactly against the direction cancels out.
+                if bent.magnitude_squared() > f32::EPSILON {
+                    bent.normalize()
+                } else {
+                    *axis
+                }
+            })
+            .map(|bent| parent + bent * distance)
+            .find(free)
+            .unwrap_or(parent + direction * distance)
+    }
+}
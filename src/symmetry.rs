@@ -0,0 +1,150 @@
+//! Symmetry groups to replicate particles with while they aggregate.
+use crate::{dla::Point3D, Result};
+use nalgebra::{Matrix3, Rotation3, Unit};
+
+/// Groups larger than this are assumed to be infinite, e.g. when two
+/// mirror planes meet at an irrational angle.
+const MAX_GROUP_SIZE: usize = 1024;
+
+/// A point group about the origin.
+///
+/// Stored as all the group's operations except for the identity.
+#[derive(Clone, Debug)]
+pub struct Symmetry {
+    operations: Vec<Matrix3<f32>>,
+}
+
+#[inline]
+fn rotation(axis: &Point3D, angle: f32) -> Matrix3<f32> {
+    Rotation3::from_axis_angle(&Unit::new_normalize(*axis), angle).into_inner()
+}
+
+#[inline]
+fn reflection(normal: &Point3D) -> Matrix3<f32> {
+    let normal = normal.normalize();
+    Matrix3::identity() - 2.0 * normal * normal.transpose()
+}
+
+/// Fails for a zero `axis`, which has no direction to turn about.
+fn check_axis(axis: &Point3D) -> Result<()> {
+    if 0.0 == axis.magnitude_squared() {
+        return Err("The symmetry axis must not be zero.".into());
+    }
+    Ok(())
+}
+
+impl Symmetry {
+    /// Builds the group generated by the given operations.
+    ///
+    /// Fails if the group keeps growing, e.g. for two mirror planes
+    /// at an angle that does not divide 180°.
+    pub fn from_generators(generators: &[Matrix3<f32>]) -> Result<Self> {
+        let mut group = vec![Matrix3::identity()];
+
+        // Multiply everything with the generators until no new
+        // operations turn up.
+        let mut i = 0;
+        while i < group.len() {
+            for generator in generators {
+                let product = generator * group[i];
+                if !group.iter().any(|g| (g - product).abs().max() < 1.0e-4) {
+                    group.push(product);
+                    if MAX_GROUP_SIZE < group.len() {
+                        return Err("Symmetry generators do not form a finite group.".into());
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        group.remove(0);
+        Ok(Self { operations: group })
+    }
+
+    /// `folds`-fold rotational symmetry about `axis`.
+    pub fn rotation(axis: &Point3D, folds: u32) -> Result<Self> {
+        check_axis(axis)?;
+        Self::from_generators(&[rotation(
+            axis,
+            std::f32::consts::TAU / folds.max(1) as f32,
+        )])
+    }
+
+    /// Reflections on the planes through the origin with the given
+    /// normals, and everything they combine into.
+    pub fn mirror(normals: &[Point3D]) -> Result<Self> {
+        if normals.iter().any(|normal| 0.0 == normal.magnitude_squared()) {
+            return Err("Mirror plane normals must not be zero.".into());
+        }
+        Self::from_generators(&normals.iter().map(reflection).collect::<Vec<_>>())
+    }
+
+    /// `folds`-fold rotational symmetry about `axis` plus as many
+    /// mirror planes containing it – the symmetry of a snowflake for
+    /// six folds.
+    pub fn dihedral(axis: &Point3D, folds: u32) -> Result<Self> {
+        check_axis(axis)?;
+        let helper = if axis.normalize().x.abs() < 0.9 {
+            Point3D::x()
+        } else {
+            Point3D::y()
+        };
+        Self::from_generators(&[
+            rotation(axis, std::f32::consts::TAU / folds.max(1) as f32),
+            reflection(&axis.cross(&helper)),
+        ])
+    }
+
+    /// The full symmetry of a cube (48 operations).
+    pub fn cubic() -> Result<Self> {
+        Self::from_generators(&[
+            rotation(&Point3D::z(), std::f32::consts::FRAC_PI_2),
+            rotation(&Point3D::new(1.0, 1.0, 1.0), std::f32::consts::TAU / 3.0),
+            -Matrix3::identity(),
+        ])
+    }
+
+    /// The full symmetry of an icosahedron (120 operations).
+    pub fn icosahedral() -> Result<Self> {
+        let golden_ratio = (1.0 + 5.0f32.sqrt()) * 0.5;
+        Self::from_generators(&[
+            rotation(
+                &Point3D::new(0.0, 1.0, golden_ratio),
+                std::f32::consts::TAU / 5.0,
+            ),
+            rotation(&Point3D::new(1.0, 1.0, 1.0), std::f32::consts::TAU / 3.0),
+            -Matrix3::identity(),
+        ])
+    }
+
+    /// Number of operations, including the identity.
+    pub fn order(&self) -> usize {
+        self.operations.len() + 1
+    }
+
+    /// Returns all images of `point` except the point itself.
+    pub fn copies<'a>(&'a self, point: &'a Point3D) -> impl Iterator<Item = Point3D> + 'a {
+        self.operations.iter().map(move |operation| operation * point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_orders() {
+        assert_eq!(Symmetry::cubic().unwrap().order(), 48);
+        assert_eq!(Symmetry::icosahedral().unwrap().order(), 120);
+        assert_eq!(Symmetry::dihedral(&Point3D::z(), 6).unwrap().order(), 12);
+        assert_eq!(Symmetry::rotation(&Point3D::y(), 5).unwrap().order(), 5);
+    }
+
+    #[test]
+    fn invalid_generators_are_errors() {
+        assert!(Symmetry::mirror(&[Point3D::x(), Point3D::new(1.0, 0.3, 0.0)]).is_err());
+        assert!(Symmetry::mirror(&[Point3D::zeros()]).is_err());
+        assert!(Symmetry::rotation(&Point3D::zeros(), 6).is_err());
+        assert!(Symmetry::dihedral(&Point3D::zeros(), 6).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Voxel grids splatted from the particles.
+use crate::{
+    dla::{Aggregate, Point3D, Square},
+    post_process::depths,
+};
+use std::io::{self, Write};
+
+/// A grid of voxels with one or more values per voxel.
+#[derive(Clone, Debug, Default)]
+pub struct Volume {
+    /// Position of the center of the first voxel.
+    pub origin: Point3D,
+    /// Edge length of a voxel.
+    pub spacing: f32,
+    /// Number of voxels along X, Y & Z.
+    pub sizes: [usize; 3],
+    /// Name & values of each channel, X varying fastest.
+    pub channels: Vec<(String, Vec<f32>)>,
+}
+
+impl Volume {
+    /// Splats all particles into a grid of `resolution` voxels along
+    /// the longest side of the aggregate's bounding box.
+    ///
+    /// Each particle adds `(1 - d²/r²)³` to the `density` channel of
+    /// the voxels within `kernel_width / 2` of it. The `age` & `depth`
+    /// channels, if asked for, hold the average of the particles'
+    /// values weighted the same way.
+    pub fn splat(
+        aggregate: &Aggregate,
+        resolution: usize,
+        kernel_width: f32,
+        channels: &[String],
+    ) -> Self {
+        let radius = 0.5 * kernel_width;
+        let (min, max) = aggregate
+            .bounding_box()
+            .unwrap_or((Point3D::zeros(), Point3D::zeros()));
+        let min = min.add_scalar(-radius);
+        let extent = max.add_scalar(radius) - min;
+        let spacing = extent.max() / resolution.max(1) as f32;
+        let sizes = [0, 1, 2].map(|axis| ((extent[axis] / spacing).ceil() as usize).max(1));
+        let origin = min.add_scalar(0.5 * spacing);
+
+        let mut volume = Volume {
+            origin,
+            spacing,
+            sizes,
+            channels: Vec::new(),
+        };
+        let voxel_count = sizes.iter().product();
+        let mut density = vec![0.0f32; voxel_count];
+
+        let depths = depths(aggregate.parents());
+        let attributes = channels
+            .iter()
+            .filter_map(|name| match name.as_str() {
+                "age" => Some((name, aggregate.ages())),
+                "depth" => Some((name, depths.as_slice())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut sums = vec![vec![0.0f32; voxel_count]; attributes.len()];
+
+        for (index, (point, _)) in aggregate.particles().iter().enumerate() {
+            let first = ((point.add_scalar(-radius) - origin) / spacing).map(|x| x.ceil().max(0.0));
+            let last = ((point.add_scalar(radius) - origin) / spacing).map(|x| x.floor());
+            for z in first.z as usize..=(last.z as usize).min(sizes[2] - 1) {
+                for y in first.y as usize..=(last.y as usize).min(sizes[1] - 1) {
+                    for x in first.x as usize..=(last.x as usize).min(sizes[0] - 1) {
+                        let voxel = volume.position([x, y, z]);
+                        let weight = (1.0 - (voxel - point).magnitude_squared() / radius.square())
+                            .max(0.0)
+                            .powi(3);
+                        let voxel = x + sizes[0] * (y + sizes[1] * z);
+                        density[voxel] += weight;
+                        for (sum, (_, values)) in sums.iter_mut().zip(&attributes) {
+                            sum[voxel] += weight * values[index] as f32;
+                        }
+                    }
+                }
+            }
+        }
+
+        for (mut sum, (name, _)) in sums.into_iter().zip(attributes) {
+            sum.iter_mut()
+                .zip(&density)
+                .filter(|(_, &density)| 0.0 < density)
+                .for_each(|(value, density)| *value /= density);
+            volume.channels.push((name.clone(), sum));
+        }
+        volume.channels.insert(0, ("density".to_string(), density));
+        volume
+    }
+
+    /// Center of the voxel at the given grid coordinates.
+    #[inline]
+    pub fn position(&self, voxel: [usize; 3]) -> Point3D {
+        self.origin + self.spacing * Point3D::new(voxel[0] as f32, voxel[1] as f32, voxel[2] as f32)
+    }
+
+    /// Writes the volume as a self-contained NRRD file with raw,
+    /// little endian floats.
+    ///
+    /// A single channel is a 3D volume. More channels become a 4D one
+    /// with the channels interleaved per voxel, as the fastest axis;
+    /// the `channels` key/value pair names them.
+    pub fn write_nrrd(&self, out: &mut dyn Write) -> io::Result<()> {
+        let multi_channel = 1 < self.channels.len();
+        let [x, y, z] = self.sizes;
+
+        writeln!(out, "NRRD0004")?;
+        writeln!(out, "# Reaction limited diffusion")?;
+        writeln!(out, "type: float")?;
+        writeln!(out, "dimension: {}", if multi_channel { 4 } else { 3 })?;
+        writeln!(out, "space: right-anterior-superior")?;
+        let spacing = self.spacing;
+        if multi_channel {
+            writeln!(out, "sizes: {} {} {} {}", self.channels.len(), x, y, z)?;
+            writeln!(
+                out,
+                "space directions: none ({0},0,0) (0,{0},0) (0,0,{0})",
+                spacing
+            )?;
+            writeln!(out, "kinds: vector domain domain domain")?;
+            writeln!(out, "labels: \"channel\" \"x\" \"y\" \"z\"")?;
+            let names = self
+                .channels
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            writeln!(out, "channels:={}", names.join(" "))?;
+        } else {
+            writeln!(out, "sizes: {} {} {}", x, y, z)?;
+            writeln!(
+                out,
+                "space directions: ({0},0,0) (0,{0},0) (0,0,{0})",
+                spacing
+            )?;
+            writeln!(out, "kinds: domain domain domain")?;
+        }
+        writeln!(
+            out,
+            "space origin: ({},{},{})",
+            self.origin.x, self.origin.y, self.origin.z
+        )?;
+        writeln!(out, "endian: little")?;
+        writeln!(out, "encoding: raw")?;
+        // A blank line ends the header.
+        writeln!(out)?;
+
+        for voxel in 0..x * y * z {
+            for (_, values) in &self.channels {
+                out.write_all(&values[voxel].to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}